@@ -32,7 +32,10 @@ use sc_service::{
 	ChainSpec, Role,
 };
 use sc_telemetry::TelemetryEndpoints;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::{
+	net::{IpAddr, Ipv4Addr, SocketAddr},
+	path::{Path, PathBuf},
+};
 
 /// The `run` command used to run a node.
 #[derive(Debug, Clone, Parser)]
@@ -116,6 +119,26 @@ pub struct RunCmd {
 	#[arg(long)]
 	pub rpc_max_subscriptions_per_connection: Option<usize>,
 
+	/// Set the maximum number of keys of a single storage subscription.
+	///
+	/// Subscriptions with more keys are rejected with a "too many subscription keys" error,
+	/// instead of running into the request size limit. Default is unlimited.
+	#[arg(long, value_name = "COUNT")]
+	pub rpc_max_subscription_keys: Option<usize>,
+
+	/// Set the maximum number of RPC methods that may be exposed.
+	///
+	/// The node refuses to start if more methods are registered. Default is unlimited.
+	#[arg(long)]
+	pub rpc_max_exposed_methods: Option<usize>,
+
+	/// Write an access log of the RPC calls to the syslog socket at this path.
+	///
+	/// Usually `/dev/log`. Records use the daemon facility. If the socket cannot be connected
+	/// to, the log is written to stderr instead. Only supported on Unix.
+	#[arg(long, value_name = "PATH")]
+	pub rpc_log_syslog: Option<PathBuf>,
+
 	/// Expose Prometheus exporter on all interfaces.
 	///
 	/// Default is local.
@@ -148,9 +171,25 @@ pub struct RunCmd {
 	/// value). Value of `all` will disable origin validation. Default is to
 	/// allow localhost and <https://polkadot.js.org> origins. When running in
 	/// --dev mode the default is to allow all origins.
+	///
+	/// Origins given here, by `--rpc-cors-file` and by the `SUBSTRATE_RPC_CORS` environment
+	/// variable are combined, in that order and without duplicates. The command line takes
+	/// precedence: `all` in the environment variable is ignored, with a warning, if origins are
+	/// given here or by `--rpc-cors-file`.
 	#[arg(long, value_name = "ORIGINS", value_parser = parse_cors)]
 	pub rpc_cors: Option<Cors>,
 
+	/// Specify a file of browser Origins allowed to access the HTTP & WS RPC servers.
+	///
+	/// One origin per line, lines starting with `#` are ignored. The origins are added to those
+	/// given by `--rpc-cors`. If no origins are given in any other way, they replace the default
+	/// list.
+	///
+	/// The file is read once at startup, and is not reloaded: the RPC servers cannot change the
+	/// origins they allow while running, so edits to the file take effect on the next restart.
+	#[arg(long, value_name = "PATH")]
+	pub rpc_cors_file: Option<PathBuf>,
+
 	/// Specify Prometheus exporter TCP Port.
 	#[arg(long, value_name = "PORT")]
 	pub prometheus_port: Option<u16>,
@@ -291,6 +330,49 @@ impl RunCmd {
 			None
 		}
 	}
+
+	/// The CORS origins of [`CliConfiguration::rpc_cors`], with `env_cors` as the value of the
+	/// `SUBSTRATE_RPC_CORS` environment variable.
+	fn rpc_cors_with_env(
+		&self,
+		is_dev: bool,
+		env_cors: Option<&str>,
+	) -> Result<Option<Vec<String>>> {
+		let file_origins =
+			self.rpc_cors_file.as_deref().map(parse_cors_file).transpose()?.map(Cors::List);
+		let mut env_origins = env_cors.map(parse_cors).transpose()?;
+		let cli_given = self.rpc_cors.is_some() || file_origins.is_some();
+		if cli_given && matches!(env_origins, Some(Cors::All)) {
+			log::warn!(
+				"{} allows all origins, but the command line restricts them; ignoring {}",
+				RPC_CORS_ENV,
+				RPC_CORS_ENV,
+			);
+			env_origins = None;
+		}
+
+		let sources = [self.rpc_cors.clone(), file_origins, env_origins];
+		let cors = merge_cors(sources).unwrap_or_else(|| {
+			if is_dev {
+				log::warn!("Running in --dev mode, RPC CORS has been disabled.");
+				Cors::All
+			} else {
+				Cors::List(vec![
+					"http://localhost:*".into(),
+					"http://127.0.0.1:*".into(),
+					"https://localhost:*".into(),
+					"https://127.0.0.1:*".into(),
+					"https://polkadot.js.org".into(),
+				])
+			}
+		});
+
+		match &cors {
+			Cors::All => log::info!("RPC CORS: all origins are allowed"),
+			Cors::List(origins) => log::info!("RPC CORS allowed origins: {}", origins.join(", ")),
+		}
+		Ok(cors.into())
+	}
 }
 
 impl CliConfiguration for RunCmd {
@@ -399,24 +481,7 @@ impl CliConfiguration for RunCmd {
 	}
 
 	fn rpc_cors(&self, is_dev: bool) -> Result<Option<Vec<String>>> {
-		Ok(self
-			.rpc_cors
-			.clone()
-			.unwrap_or_else(|| {
-				if is_dev {
-					log::warn!("Running in --dev mode, RPC CORS has been disabled.");
-					Cors::All
-				} else {
-					Cors::List(vec![
-						"http://localhost:*".into(),
-						"http://127.0.0.1:*".into(),
-						"https://localhost:*".into(),
-						"https://127.0.0.1:*".into(),
-						"https://polkadot.js.org".into(),
-					])
-				}
-			})
-			.into())
+		self.rpc_cors_with_env(is_dev, std::env::var(RPC_CORS_ENV).ok().as_deref())
 	}
 
 	fn rpc_http(&self, default_listen_port: u16) -> Result<Option<SocketAddr>> {
@@ -465,6 +530,18 @@ impl CliConfiguration for RunCmd {
 		Ok(self.rpc_max_subscriptions_per_connection)
 	}
 
+	fn rpc_max_subscription_keys(&self) -> Result<Option<usize>> {
+		Ok(self.rpc_max_subscription_keys)
+	}
+
+	fn rpc_max_exposed_methods(&self) -> Result<Option<usize>> {
+		Ok(self.rpc_max_exposed_methods)
+	}
+
+	fn rpc_log_syslog(&self) -> Result<Option<PathBuf>> {
+		Ok(self.rpc_log_syslog.clone())
+	}
+
 	fn ws_max_out_buffer_capacity(&self) -> Result<Option<usize>> {
 		Ok(self.ws_max_out_buffer_capacity)
 	}
@@ -596,6 +673,30 @@ impl From<Cors> for Option<Vec<String>> {
 	}
 }
 
+/// Environment variable with comma-separated RPC CORS origins, in the format of `--rpc-cors`.
+const RPC_CORS_ENV: &str = "SUBSTRATE_RPC_CORS";
+
+/// Combine the origins of several CORS sources, in order and without duplicates.
+///
+/// Returns `None` if no source is given, and [`Cors::All`] if any of them allows all origins.
+fn merge_cors(sources: impl IntoIterator<Item = Option<Cors>>) -> Option<Cors> {
+	let mut merged: Option<Vec<String>> = None;
+	for cors in sources.into_iter().flatten() {
+		match cors {
+			Cors::All => return Some(Cors::All),
+			Cors::List(origins) => {
+				let merged = merged.get_or_insert_with(Vec::new);
+				for origin in origins {
+					if !merged.contains(&origin) {
+						merged.push(origin);
+					}
+				}
+			},
+		}
+	}
+	merged.map(Cors::List)
+}
+
 /// Parse cors origins.
 fn parse_cors(s: &str) -> Result<Cors> {
 	let mut is_all = false;
@@ -617,9 +718,95 @@ fn parse_cors(s: &str) -> Result<Cors> {
 	}
 }
 
+/// Read cors origins from a file, one per line, skipping empty lines and `#` comments.
+fn parse_cors_file(path: &Path) -> Result<Vec<String>> {
+	Ok(std::fs::read_to_string(path)?
+		.lines()
+		.map(str::trim)
+		.filter(|line| !line.is_empty() && !line.starts_with('#'))
+		.map(ToOwned::to_owned)
+		.collect())
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
+	use std::io::Write;
+
+	#[test]
+	fn cors_file_is_combined_with_inline_origins() {
+		let mut file = tempfile::NamedTempFile::new().unwrap();
+		writeln!(file, "# dashboards\nhttps://a.example\n\n  https://b.example  ").unwrap();
+
+		let cmd = RunCmd::parse_from([
+			"run",
+			"--rpc-cors",
+			"https://inline.example",
+			"--rpc-cors-file",
+			file.path().to_str().unwrap(),
+		]);
+		assert_eq!(
+			cmd.rpc_cors_with_env(false, None).unwrap(),
+			Some(vec![
+				"https://inline.example".to_string(),
+				"https://a.example".to_string(),
+				"https://b.example".to_string(),
+			]),
+		);
+
+		let cmd = RunCmd::parse_from(["run", "--rpc-cors-file", file.path().to_str().unwrap()]);
+		assert_eq!(
+			cmd.rpc_cors_with_env(false, None).unwrap(),
+			Some(vec!["https://a.example".to_string(), "https://b.example".to_string()]),
+		);
+	}
+
+	#[test]
+	fn cors_env_is_combined_with_inline_origins() {
+		let cmd = RunCmd::parse_from(["run", "--rpc-cors", "https://a.example,https://b.example"]);
+		assert_eq!(
+			cmd.rpc_cors_with_env(false, Some("https://b.example,https://c.example")).unwrap(),
+			Some(vec![
+				"https://a.example".to_string(),
+				"https://b.example".to_string(),
+				"https://c.example".to_string(),
+			]),
+		);
+		// the explicit list is not overridden by the environment.
+		assert_eq!(
+			cmd.rpc_cors_with_env(false, Some("all")).unwrap(),
+			Some(vec!["https://a.example".to_string(), "https://b.example".to_string()]),
+		);
+		let cmd = RunCmd::parse_from(["run", "--rpc-cors", "all"]);
+		assert_eq!(cmd.rpc_cors_with_env(false, Some("https://a.example")).unwrap(), None);
+
+		let cmd = RunCmd::parse_from(["run"]);
+		assert_eq!(cmd.rpc_cors_with_env(false, Some("all")).unwrap(), None);
+		assert_eq!(cmd.rpc_cors_with_env(true, None).unwrap(), None);
+		assert_eq!(
+			cmd.rpc_cors_with_env(true, Some("https://a.example")).unwrap(),
+			Some(vec!["https://a.example".to_string()]),
+		);
+	}
+
+	#[test]
+	fn cors_sources_are_merged_without_duplicates() {
+		let inline = Cors::List(vec!["https://a.example".into(), "https://b.example".into()]);
+		let env = Cors::List(vec!["https://b.example".into(), "https://c.example".into()]);
+		let merged: Option<Vec<String>> =
+			merge_cors([Some(inline.clone()), None, Some(env)]).unwrap().into();
+		assert_eq!(
+			merged,
+			Some(vec![
+				"https://a.example".to_string(),
+				"https://b.example".to_string(),
+				"https://c.example".to_string(),
+			]),
+		);
+
+		assert!(matches!(merge_cors([Some(inline), Some(Cors::All)]), Some(Cors::All)));
+		assert!(merge_cors([None, None, None]).is_none());
+	}
 
 	#[test]
 	fn tests_node_name_good() {
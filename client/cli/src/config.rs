@@ -365,6 +365,25 @@ pub trait CliConfiguration<DCV: DefaultConfigurationValues = ()>: Sized {
 		Ok(None)
 	}
 
+	/// Get maximum number of keys of a single storage subscription.
+	///
+	/// By default this is `None`, which means unlimited.
+	fn rpc_max_subscription_keys(&self) -> Result<Option<usize>> {
+		Ok(None)
+	}
+
+	/// Get maximum number of exposed RPC methods.
+	fn rpc_max_exposed_methods(&self) -> Result<Option<usize>> {
+		Ok(None)
+	}
+
+	/// Get the path of the syslog socket the RPC access log is written to.
+	///
+	/// By default this is `None`.
+	fn rpc_log_syslog(&self) -> Result<Option<PathBuf>> {
+		Ok(None)
+	}
+
 	/// Get maximum WS output buffer capacity.
 	fn ws_max_out_buffer_capacity(&self) -> Result<Option<usize>> {
 		Ok(None)
@@ -544,6 +563,9 @@ pub trait CliConfiguration<DCV: DefaultConfigurationValues = ()>: Sized {
 			rpc_max_response_size: self.rpc_max_response_size()?,
 			rpc_id_provider: None,
 			rpc_max_subs_per_conn: self.rpc_max_subscriptions_per_connection()?,
+			rpc_max_subscription_keys: self.rpc_max_subscription_keys()?,
+			rpc_max_exposed_methods: self.rpc_max_exposed_methods()?,
+			rpc_log_syslog: self.rpc_log_syslog()?,
 			ws_max_out_buffer_capacity: self.ws_max_out_buffer_capacity()?,
 			prometheus_config: self
 				.prometheus_config(DCV::prometheus_listen_port(), &chain_spec)?,
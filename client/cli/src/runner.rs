@@ -324,6 +324,9 @@ mod tests {
 				rpc_max_response_size: None,
 				rpc_id_provider: None,
 				rpc_max_subs_per_conn: None,
+				rpc_max_subscription_keys: None,
+				rpc_max_exposed_methods: None,
+				rpc_log_syslog: None,
 				ws_max_out_buffer_capacity: None,
 				prometheus_config: None,
 				telemetry_endpoints: None,
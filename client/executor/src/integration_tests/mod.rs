@@ -431,6 +431,22 @@ fn offchain_http_should_work(wasm_method: WasmExecutionMethod) {
 	);
 }
 
+test_wasm_execution!(allocation_stats_are_returned);
+fn allocation_stats_are_returned(wasm_method: WasmExecutionMethod) {
+	let mut ext = TestExternalities::default();
+	let executor = crate::WasmExecutor::<HostFunctions>::new(wasm_method, Some(1024), 8, None, 2);
+
+	let (result, stats) = executor.call_with_allocation_stats(
+		wasm_binary_unwrap(),
+		&mut ext.ext(),
+		"test_allocate_vec",
+		&1048576_u32.encode(),
+	);
+
+	result.unwrap();
+	assert!(stats.unwrap().bytes_allocated_peak >= 1048576);
+}
+
 test_wasm_execution!(should_trap_when_heap_exhausted);
 fn should_trap_when_heap_exhausted(wasm_method: WasmExecutionMethod) {
 	let mut ext = TestExternalities::default();
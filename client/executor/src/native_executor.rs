@@ -248,6 +248,27 @@ where
 		(result, allocation_stats)
 	}
 
+	/// Call `export_name` of the runtime `code` with `call_data`, and also return the statistics
+	/// of the allocations on its wasm heap.
+	///
+	/// `code` may be compressed. The runtime is instantiated for this call only, bypassing the
+	/// runtime cache, with the parameters this `WasmExecutor` was initialized with. All the host
+	/// functions imported by the runtime must be provided. The statistics are `None` if the call
+	/// failed before the runtime was entered.
+	pub fn call_with_allocation_stats(
+		&self,
+		code: &[u8],
+		ext: &mut dyn Externalities,
+		export_name: &str,
+		call_data: &[u8],
+	) -> (std::result::Result<Vec<u8>, Error>, Option<AllocationStats>) {
+		let runtime_blob = match RuntimeBlob::uncompress_if_needed(code) {
+			Ok(runtime_blob) => runtime_blob,
+			Err(e) => return (Err(e.into()), None),
+		};
+		self.uncached_call_with_allocation_stats(runtime_blob, ext, false, export_name, call_data)
+	}
+
 	fn uncached_call_impl(
 		&self,
 		runtime_blob: RuntimeBlob,
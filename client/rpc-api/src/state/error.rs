@@ -49,6 +49,14 @@ pub enum Error {
 		/// Maximum allowed value
 		max: u32,
 	},
+	/// A storage subscription was requested for too many keys.
+	#[error("too many subscription keys: {}, max: {}", .count, .max)]
+	TooManySubscriptionKeys {
+		/// Number of keys requested
+		count: usize,
+		/// Maximum allowed number of keys
+		max: usize,
+	},
 	/// Call to an unsafe RPC was denied.
 	#[error(transparent)]
 	UnsafeRpcCalled(#[from] crate::policy::UnsafeRpcError),
@@ -66,6 +74,9 @@ impl From<Error> for JsonRpseeError {
 			Error::InvalidCount { .. } =>
 				CallError::Custom(ErrorObject::owned(BASE_ERROR + 2, e.to_string(), None::<()>))
 					.into(),
+			Error::TooManySubscriptionKeys { .. } =>
+				CallError::Custom(ErrorObject::owned(BASE_ERROR + 3, e.to_string(), None::<()>))
+					.into(),
 			e => Self::to_call_error(e),
 		}
 	}
@@ -25,9 +25,11 @@ use jsonrpsee::{
 	ws_server::{WsServerBuilder, WsServerHandle},
 	RpcModule,
 };
-use std::{error::Error as StdError, net::SocketAddr};
+use std::{error::Error as StdError, net::SocketAddr, ops::Deref, sync::Arc};
 
-pub use crate::middleware::{RpcMetrics, RpcMiddleware};
+pub use crate::middleware::{RpcLogger, RpcMetrics, RpcMiddleware};
+#[cfg(unix)]
+pub use crate::syslog::{SyslogFacility, SyslogLogger};
 pub use jsonrpsee::core::{
 	id_providers::{RandomIntegerIdProvider, RandomStringIdProvider},
 	traits::IdProvider,
@@ -38,6 +40,9 @@ const MEGABYTE: usize = 1024 * 1024;
 /// Maximal payload accepted by RPC servers.
 pub const RPC_MAX_PAYLOAD_DEFAULT: usize = 15 * MEGABYTE;
 
+/// Payload size above which a warning is logged, since hardly any deployment needs it.
+const RPC_MAX_PAYLOAD_ADVISORY: usize = 2048 * MEGABYTE;
+
 /// Default maximum number of connections for WS RPC servers.
 const WS_MAX_CONNECTIONS: usize = 100;
 
@@ -45,11 +50,39 @@ const WS_MAX_CONNECTIONS: usize = 100;
 const WS_MAX_SUBS_PER_CONN: usize = 1024;
 
 pub mod middleware;
+#[cfg(unix)]
+pub mod syslog;
 
 /// Type alias for http server
-pub type HttpServer = HttpServerHandle;
+pub type HttpServer = ServerHandle<HttpServerHandle>;
 /// Type alias for ws server
-pub type WsServer = WsServerHandle;
+pub type WsServer = ServerHandle<WsServerHandle>;
+
+/// The handle of a running server, together with the CORS policy applied to it.
+pub struct ServerHandle<H> {
+	handle: H,
+	allowed_origins: Option<Vec<String>>,
+}
+
+impl<H> ServerHandle<H> {
+	/// The origins allowed by the CORS policy of the server, or `None` if any origin is allowed.
+	pub fn allowed_origins(&self) -> Option<&[String]> {
+		self.allowed_origins.as_deref()
+	}
+
+	/// Unwrap the handle of the server, e.g. to stop it.
+	pub fn into_inner(self) -> H {
+		self.handle
+	}
+}
+
+impl<H> Deref for ServerHandle<H> {
+	type Target = H;
+
+	fn deref(&self) -> &H {
+		&self.handle
+	}
+}
 
 /// WebSocket specific settings on the server.
 pub struct WsConfig {
@@ -61,16 +94,49 @@ pub struct WsConfig {
 	pub max_payload_in_mb: Option<usize>,
 	/// Maximum rpc response payload size.
 	pub max_payload_out_mb: Option<usize>,
+	/// Maximum number of exposed methods.
+	pub max_exposed_methods: Option<usize>,
 }
 
 impl WsConfig {
+	/// Limits scaled to the memory and number of CPUs of this machine.
+	///
+	/// See [`WsConfig::scaled_to`] for the heuristic. If the memory can not be detected, which is
+	/// the case on platforms other than Linux, the default limits are used.
+	pub fn auto_scaled() -> Self {
+		let cpus = std::thread::available_parallelism().map_or(1, |cpus| cpus.get());
+		Self::scaled_to(total_memory().unwrap_or_default(), cpus)
+	}
+
+	/// Limits scaled to `memory` bytes of memory and `cpus` CPUs.
+	///
+	/// The heuristic allows 25 connections per GiB of memory, capped at 250 connections per CPU,
+	/// and never less than the default of 100 or more than 10_000 connections. Subscription and
+	/// payload limits are left at their defaults, since they bound a single connection.
+	pub fn scaled_to(memory: u64, cpus: usize) -> Self {
+		let memory_gib = (memory / (1024 * MEGABYTE as u64)) as usize;
+		let max_connections = memory_gib
+			.saturating_mul(25)
+			.min(cpus.saturating_mul(250))
+			.clamp(WS_MAX_CONNECTIONS, 10_000);
+
+		Self {
+			max_connections: Some(max_connections),
+			max_subs_per_conn: None,
+			max_payload_in_mb: None,
+			max_payload_out_mb: None,
+			max_exposed_methods: None,
+		}
+	}
+
 	// Deconstructs the config to get the finalized inner values.
 	//
-	// `Payload size` or `max subs per connection` bigger than u32::MAX will be truncated.
+	// `Payload size` bigger than u32::MAX is clamped to u32::MAX, `max subs per connection`
+	// bigger than u32::MAX will be truncated.
 	fn deconstruct(self) -> (u32, u32, u64, u32) {
 		let max_conns = self.max_connections.unwrap_or(WS_MAX_CONNECTIONS) as u64;
-		let max_payload_in_mb = payload_size_or_default(self.max_payload_in_mb) as u32;
-		let max_payload_out_mb = payload_size_or_default(self.max_payload_out_mb) as u32;
+		let max_payload_in_mb = payload_size_or_default(self.max_payload_in_mb);
+		let max_payload_out_mb = payload_size_or_default(self.max_payload_out_mb);
 		let max_subs_per_conn = self.max_subs_per_conn.unwrap_or(WS_MAX_SUBS_PER_CONN) as u32;
 
 		(max_payload_in_mb, max_payload_out_mb, max_conns, max_subs_per_conn)
@@ -83,32 +149,38 @@ pub async fn start_http<M: Send + Sync + 'static>(
 	cors: Option<&Vec<String>>,
 	max_payload_in_mb: Option<usize>,
 	max_payload_out_mb: Option<usize>,
+	max_exposed_methods: Option<usize>,
 	metrics: Option<RpcMetrics>,
+	logger: Option<Box<dyn RpcLogger>>,
 	rpc_api: RpcModule<M>,
 	rt: tokio::runtime::Handle,
-) -> Result<HttpServerHandle, Box<dyn StdError + Send + Sync>> {
+) -> Result<HttpServer, Box<dyn StdError + Send + Sync>> {
+	if let Some(advisory) = payload_advisory(&[max_payload_in_mb, max_payload_out_mb]) {
+		log::warn!("{}", advisory);
+	}
 	let max_payload_in = payload_size_or_default(max_payload_in_mb);
 	let max_payload_out = payload_size_or_default(max_payload_out_mb);
 
 	let mut acl = AccessControlBuilder::new();
 
-	if let Some(cors) = cors {
-		// Whitelist listening address.
-		// NOTE: set_allowed_hosts will whitelist both ports but only one will used.
-		acl = acl.set_allowed_hosts(format_allowed_hosts(&addrs[..]))?;
+	// Whitelist listening address.
+	// NOTE: set_allowed_hosts will whitelist both ports but only one will used.
+	let allowed_hosts = cors.map(|_| format_allowed_hosts(&addrs[..]));
+	if let (Some(cors), Some(hosts)) = (cors, &allowed_hosts) {
+		acl = acl.set_allowed_hosts(hosts.clone())?;
 		acl = acl.set_allowed_origins(cors)?;
 	};
 
 	let builder = HttpServerBuilder::new()
-		.max_request_body_size(max_payload_in as u32)
-		.max_response_body_size(max_payload_out as u32)
+		.max_request_body_size(max_payload_in)
+		.max_response_body_size(max_payload_out)
 		.set_access_control(acl.build())
 		.health_api("/health", "system_health")?
 		.custom_tokio_runtime(rt);
 
-	let rpc_api = build_rpc_api(rpc_api);
-	let (handle, addr) = if let Some(metrics) = metrics {
-		let middleware = RpcMiddleware::new(metrics, "http".into());
+	let rpc_api = build_rpc_api(rpc_api, max_exposed_methods, metrics.as_ref(), allowed_hosts)?;
+	let (handle, addr) = if metrics.is_some() || logger.is_some() {
+		let middleware = RpcMiddleware::with_logger(metrics, logger.map(Arc::from), "http");
 		let builder = builder.set_middleware(middleware);
 		let server = builder.build(&addrs[..]).await?;
 		let addr = server.local_addr();
@@ -125,7 +197,7 @@ pub async fn start_http<M: Send + Sync + 'static>(
 		cors
 	);
 
-	Ok(handle)
+	Ok(ServerHandle { handle, allowed_origins: cors.cloned() })
 }
 
 /// Start WS server listening on given address.
@@ -134,19 +206,27 @@ pub async fn start_ws<M: Send + Sync + 'static>(
 	cors: Option<&Vec<String>>,
 	ws_config: WsConfig,
 	metrics: Option<RpcMetrics>,
+	logger: Option<Box<dyn RpcLogger>>,
 	rpc_api: RpcModule<M>,
 	rt: tokio::runtime::Handle,
 	id_provider: Option<Box<dyn IdProvider>>,
-) -> Result<WsServerHandle, Box<dyn StdError + Send + Sync>> {
+) -> Result<WsServer, Box<dyn StdError + Send + Sync>> {
+	if let Some(advisory) =
+		payload_advisory(&[ws_config.max_payload_in_mb, ws_config.max_payload_out_mb])
+	{
+		log::warn!("{}", advisory);
+	}
+	let max_exposed_methods = ws_config.max_exposed_methods;
 	let (max_payload_in, max_payload_out, max_connections, max_subs_per_conn) =
 		ws_config.deconstruct();
 
 	let mut acl = AccessControlBuilder::new();
 
-	if let Some(cors) = cors {
-		// Whitelist listening address.
-		// NOTE: set_allowed_hosts will whitelist both ports but only one will used.
-		acl = acl.set_allowed_hosts(format_allowed_hosts(&addrs[..]))?;
+	// Whitelist listening address.
+	// NOTE: set_allowed_hosts will whitelist both ports but only one will used.
+	let allowed_hosts = cors.map(|_| format_allowed_hosts(&addrs[..]));
+	if let (Some(cors), Some(hosts)) = (cors, &allowed_hosts) {
+		acl = acl.set_allowed_hosts(hosts.clone())?;
 		acl = acl.set_allowed_origins(cors)?;
 	};
 
@@ -165,9 +245,9 @@ pub async fn start_ws<M: Send + Sync + 'static>(
 		builder = builder.set_id_provider(RandomStringIdProvider::new(16));
 	};
 
-	let rpc_api = build_rpc_api(rpc_api);
-	let (handle, addr) = if let Some(metrics) = metrics {
-		let middleware = RpcMiddleware::new(metrics, "ws".into());
+	let rpc_api = build_rpc_api(rpc_api, max_exposed_methods, metrics.as_ref(), allowed_hosts)?;
+	let (handle, addr) = if metrics.is_some() || logger.is_some() {
+		let middleware = RpcMiddleware::with_logger(metrics, logger.map(Arc::from), "ws");
 		let builder = builder.set_middleware(middleware);
 		let server = builder.build(&addrs[..]).await?;
 		let addr = server.local_addr();
@@ -184,7 +264,7 @@ pub async fn start_ws<M: Send + Sync + 'static>(
 		cors
 	);
 
-	Ok(handle)
+	Ok(ServerHandle { handle, allowed_origins: cors.cloned() })
 }
 
 fn format_allowed_hosts(addrs: &[SocketAddr]) -> Vec<String> {
@@ -196,10 +276,43 @@ fn format_allowed_hosts(addrs: &[SocketAddr]) -> Vec<String> {
 	hosts
 }
 
-fn build_rpc_api<M: Send + Sync + 'static>(mut rpc_api: RpcModule<M>) -> RpcModule<M> {
+fn build_rpc_api<M: Send + Sync + 'static>(
+	mut rpc_api: RpcModule<M>,
+	max_exposed_methods: Option<usize>,
+	metrics: Option<&RpcMetrics>,
+	allowed_hosts: Option<Vec<String>>,
+) -> Result<RpcModule<M>, String> {
+	// Only used by the methods registered in test and debug builds.
+	#[cfg(not(all(feature = "test-helpers", debug_assertions)))]
+	let _ = metrics;
+	#[cfg(not(debug_assertions))]
+	let _ = allowed_hosts;
+
+	// Never exposed by release builds, even with the feature on.
+	#[cfg(all(feature = "test-helpers", debug_assertions))]
+	if let Some(metrics) = metrics.cloned() {
+		rpc_api
+			.register_method("rpc_resetMetrics", move |_, _| {
+				metrics.reset();
+				Ok(())
+			})
+			.map_err(|e| format!("failed to register rpc_resetMetrics: {}", e))?;
+	}
+
 	let mut available_methods = rpc_api.method_names().collect::<Vec<_>>();
 	available_methods.sort();
 
+	if let Some(max) = max_exposed_methods {
+		if available_methods.len() > max {
+			return Err(format!(
+				"{} RPC methods exposed, but at most {} are allowed; over the limit: {:?}",
+				available_methods.len(),
+				max,
+				&available_methods[max..],
+			))
+		}
+	}
+
 	rpc_api
 		.register_method("rpc_methods", move |_, _| {
 			Ok(serde_json::json!({
@@ -208,9 +321,213 @@ fn build_rpc_api<M: Send + Sync + 'static>(mut rpc_api: RpcModule<M>) -> RpcModu
 		})
 		.expect("infallible all other methods have their own address space; qed");
 
+	// Diagnostics for host filtering, not counted against `max_exposed_methods` and not listed by
+	// `rpc_methods`. `None` means that host filtering is disabled.
+	#[cfg(debug_assertions)]
 	rpc_api
+		.register_method("system_rpcAllowedHosts", move |_, _| {
+			Ok(match &allowed_hosts {
+				Some(hosts) => serde_json::json!(hosts),
+				None => serde_json::json!("any"),
+			})
+		})
+		.map_err(|e| format!("failed to register system_rpcAllowedHosts: {}", e))?;
+
+	Ok(rpc_api)
 }
 
-fn payload_size_or_default(size_mb: Option<usize>) -> usize {
-	size_mb.map_or(RPC_MAX_PAYLOAD_DEFAULT, |mb| mb.saturating_mul(MEGABYTE))
+// Total memory of this machine in bytes, if it can be detected.
+fn total_memory() -> Option<u64> {
+	let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+	let kib = meminfo
+		.lines()
+		.find_map(|line| line.strip_prefix("MemTotal:"))?
+		.trim()
+		.strip_suffix("kB")?
+		.trim()
+		.parse::<u64>()
+		.ok()?;
+	Some(kib * 1024)
+}
+
+// Converts a payload size in megabytes to bytes, as accepted by jsonrpsee.
+//
+// Sizes that do not fit in a `u32` are clamped to `u32::MAX` bytes (4 GiB).
+fn payload_size_or_default(size_mb: Option<usize>) -> u32 {
+	let size = size_mb.map_or(RPC_MAX_PAYLOAD_DEFAULT, |mb| mb.saturating_mul(MEGABYTE));
+	u32::try_from(size).unwrap_or(u32::MAX)
+}
+
+// A warning about the payload sizes of a server, if the largest of them is above
+// `RPC_MAX_PAYLOAD_ADVISORY`.
+fn payload_advisory(sizes_mb: &[Option<usize>]) -> Option<String> {
+	let largest_mb = sizes_mb.iter().flatten().copied().max()?;
+	if largest_mb.saturating_mul(MEGABYTE) <= RPC_MAX_PAYLOAD_ADVISORY {
+		return None
+	}
+	Some(format!(
+		"RPC payload size of {} MB is unusually large; using {} bytes, the maximum is {} bytes",
+		largest_mb,
+		payload_size_or_default(Some(largest_mb)),
+		u32::MAX,
+	))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn module_with_methods(methods: &[&'static str]) -> RpcModule<()> {
+		let mut module = RpcModule::new(());
+		for method in methods {
+			module.register_method(*method, |_, _| Ok(())).unwrap();
+		}
+		module
+	}
+
+	#[test]
+	fn payload_size_is_clamped_to_u32() {
+		assert_eq!(payload_size_or_default(None), RPC_MAX_PAYLOAD_DEFAULT as u32);
+		assert_eq!(payload_size_or_default(Some(1)), MEGABYTE as u32);
+		assert_eq!(payload_size_or_default(Some(4096)), u32::MAX);
+		assert_eq!(payload_size_or_default(Some(usize::MAX)), u32::MAX);
+
+		let ws_config = WsConfig {
+			max_connections: None,
+			max_subs_per_conn: None,
+			max_payload_in_mb: Some(usize::MAX),
+			max_payload_out_mb: Some(usize::MAX),
+			max_exposed_methods: None,
+		};
+		let (max_payload_in, max_payload_out, _, _) = ws_config.deconstruct();
+		assert_eq!(max_payload_in, u32::MAX);
+		assert_eq!(max_payload_out, u32::MAX);
+	}
+
+	#[test]
+	fn payload_advisory_only_above_the_threshold() {
+		let advisory_mb = RPC_MAX_PAYLOAD_ADVISORY / MEGABYTE;
+		assert_eq!(payload_advisory(&[None, None]), None);
+		assert_eq!(payload_advisory(&[Some(advisory_mb), Some(1)]), None);
+
+		let advisory = payload_advisory(&[Some(1), Some(advisory_mb + 1)]).unwrap();
+		assert!(advisory.contains(&format!("{} MB", advisory_mb + 1)), "{}", advisory);
+		// one warning for all the sizes of a server.
+		let advisory = payload_advisory(&[Some(4096), Some(usize::MAX)]).unwrap();
+		assert!(advisory.starts_with(&format!("RPC payload size of {} MB", usize::MAX)));
+	}
+
+	#[test]
+	fn scaled_config_grows_with_memory() {
+		const GIB: u64 = 1024 * 1024 * 1024;
+
+		let small = WsConfig::scaled_to(4 * GIB, 4).max_connections.unwrap();
+		let large = WsConfig::scaled_to(64 * GIB, 16).max_connections.unwrap();
+		assert_eq!(small, WS_MAX_CONNECTIONS);
+		assert_eq!(large, 1600);
+		assert!(large > small);
+
+		assert_eq!(WsConfig::scaled_to(1024 * GIB, 1024).max_connections, Some(10_000));
+		assert_eq!(WsConfig::scaled_to(0, 0).max_connections, Some(WS_MAX_CONNECTIONS));
+	}
+
+	#[test]
+	fn max_exposed_methods_exceeded_names_overflow() {
+		let module = module_with_methods(&["a_one", "b_two", "c_three"]);
+		let err = build_rpc_api(module, Some(2), None, None).unwrap_err();
+		assert!(err.contains("c_three"));
+		assert!(!err.contains("a_one"));
+	}
+
+	#[test]
+	fn max_exposed_methods_within_limit() {
+		let module = module_with_methods(&["a_one", "b_two"]);
+		let rpc_api = build_rpc_api(module, Some(2), None, None).unwrap();
+		assert!(rpc_api.method_names().any(|m| m == "rpc_methods"));
+	}
+
+	#[tokio::test]
+	async fn allowed_origins_are_reported() {
+		let start = |cors: Option<Vec<String>>| async move {
+			start_http(
+				["127.0.0.1:0".parse().unwrap(), "127.0.0.1:0".parse().unwrap()],
+				cors.as_ref(),
+				None,
+				None,
+				None,
+				None,
+				None,
+				RpcModule::new(()),
+				tokio::runtime::Handle::current(),
+			)
+			.await
+			.unwrap()
+		};
+
+		let origins = vec!["http://localhost:3000".to_string(), "https://example.com".to_string()];
+		let server = start(Some(origins.clone())).await;
+		assert_eq!(server.allowed_origins(), Some(&origins[..]));
+		server.into_inner().stop().unwrap();
+
+		let server = start(None).await;
+		assert_eq!(server.allowed_origins(), None);
+		server.into_inner().stop().unwrap();
+	}
+
+	#[cfg(all(feature = "test-helpers", debug_assertions))]
+	#[test]
+	fn metrics_are_reset_over_rpc() {
+		use jsonrpsee::{
+			core::middleware::{MethodKind, Params, WsMiddleware},
+			types::EmptyParams,
+		};
+
+		let registry = prometheus_endpoint::Registry::new();
+		let metrics = RpcMetrics::new(Some(&registry)).unwrap().unwrap();
+		let middleware = RpcMiddleware::new(metrics.clone(), "ws");
+		let total = |name| {
+			registry
+				.gather()
+				.iter()
+				.filter(|family| family.get_name() == name)
+				.flat_map(|family| family.get_metric())
+				.map(|metric| metric.get_counter().get_value())
+				.sum::<f64>()
+		};
+
+		WsMiddleware::on_request(&middleware);
+		let params = Params::new(None);
+		WsMiddleware::on_call(&middleware, "system_name", params, MethodKind::MethodCall);
+		assert_eq!(total("substrate_rpc_requests_started"), 1.0);
+		assert_eq!(total("substrate_rpc_calls_started"), 1.0);
+
+		let rpc_api = build_rpc_api(module_with_methods(&[]), None, Some(&metrics), None).unwrap();
+		futures::executor::block_on(rpc_api.call::<_, ()>("rpc_resetMetrics", EmptyParams::new()))
+			.unwrap();
+
+		assert_eq!(total("substrate_rpc_requests_started"), 0.0);
+		assert_eq!(total("substrate_rpc_calls_started"), 0.0);
+	}
+
+	#[cfg(debug_assertions)]
+	#[test]
+	fn allowed_hosts_are_exposed() {
+		use jsonrpsee::types::EmptyParams;
+
+		let call = |allowed_hosts| {
+			let rpc_api =
+				build_rpc_api(module_with_methods(&[]), None, None, allowed_hosts).unwrap();
+			futures::executor::block_on(
+				rpc_api.call::<_, serde_json::Value>("system_rpcAllowedHosts", EmptyParams::new()),
+			)
+			.unwrap()
+		};
+
+		let addrs = ["127.0.0.1:9944".parse().unwrap(), "127.0.0.1:0".parse().unwrap()];
+		assert_eq!(
+			call(Some(format_allowed_hosts(&addrs))),
+			serde_json::json!(["localhost:9944", "127.0.0.1:9944", "localhost:0", "127.0.0.1:0"]),
+		);
+		assert_eq!(call(None), serde_json::json!("any"));
+	}
 }
@@ -16,14 +16,20 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
-//! RPC middlware to collect prometheus metrics on RPC calls.
+//! RPC middlware to collect prometheus metrics on RPC calls, and to forward the lifecycle events
+//! of RPC calls to a custom [`RpcLogger`].
 
 use jsonrpsee::core::middleware::{Headers, HttpMiddleware, MethodKind, Params, WsMiddleware};
 use prometheus_endpoint::{
-	register, Counter, CounterVec, HistogramOpts, HistogramVec, Opts, PrometheusError, Registry,
-	U64,
+	register, Counter, CounterVec, Gauge, HistogramOpts, HistogramVec, Opts, PrometheusError,
+	Registry, U64,
+};
+use std::{
+	collections::HashMap,
+	net::SocketAddr,
+	sync::{Arc, Mutex},
+	time::{Duration, Instant},
 };
-use std::net::SocketAddr;
 
 /// Histogram time buckets in microseconds.
 const HISTOGRAM_BUCKETS: [f64; 11] = [
@@ -40,6 +46,31 @@ const HISTOGRAM_BUCKETS: [f64; 11] = [
 	10_000_000.0,
 ];
 
+/// The namespaces of the RPC methods that Substrate based nodes commonly expose.
+///
+/// Calls of unknown methods are counted by these, so that arbitrary method names sent by clients
+/// can not blow up the cardinality of the metric labels.
+const KNOWN_METHOD_PREFIXES: [&str; 18] = [
+	"author",
+	"babe",
+	"beefy",
+	"chain",
+	"chainHead",
+	"childstate",
+	"contracts",
+	"dev",
+	"grandpa",
+	"mmr",
+	"offchain",
+	"payment",
+	"rpc",
+	"state",
+	"statement",
+	"sync",
+	"system",
+	"transaction",
+];
+
 /// Metrics for RPC middleware storing information about the number of requests started/completed,
 /// calls started/completed and their timings.
 #[derive(Debug, Clone)]
@@ -58,6 +89,12 @@ pub struct RpcMetrics {
 	ws_sessions_opened: Option<Counter<U64>>,
 	/// Number of Websocket sessions closed (Websocket only).
 	ws_sessions_closed: Option<Counter<U64>>,
+	/// Number of Websocket sessions currently open (Websocket only).
+	ws_sessions_open: Gauge<U64>,
+	/// Number of HTTP requests and Websocket sessions by bucket of their `Origin` header.
+	requests_by_origin: CounterVec<U64>,
+	/// Number of calls of methods that are not registered, by the prefix of the method name.
+	methods_not_found: CounterVec<U64>,
 }
 
 impl RpcMetrics {
@@ -132,30 +169,187 @@ impl RpcMetrics {
 					metrics_registry,
 				)?
 				.into(),
+				ws_sessions_open: register(
+					Gauge::new(
+						"substrate_rpc_sessions_open",
+						"Number of persistent RPC sessions currently open",
+					)?,
+					metrics_registry,
+				)?,
+				requests_by_origin: register(
+					CounterVec::new(
+						Opts::new(
+							"substrate_rpc_requests_by_origin",
+							"Number of HTTP requests and Websocket sessions by origin bucket",
+						),
+						&["protocol", "origin"],
+					)?,
+					metrics_registry,
+				)?,
+				methods_not_found: register(
+					CounterVec::new(
+						Opts::new(
+							"substrate_rpc_methods_not_found",
+							"Number of calls of unknown RPC methods by method prefix",
+						),
+						&["protocol", "prefix"],
+					)?,
+					metrics_registry,
+				)?,
 			}))
 		} else {
 			Ok(None)
 		}
 	}
+
+	/// Reset all counters and histograms to zero.
+	#[cfg(all(feature = "test-helpers", debug_assertions))]
+	pub fn reset(&self) {
+		self.requests_started.reset();
+		self.requests_finished.reset();
+		self.calls_time.reset();
+		self.calls_started.reset();
+		self.calls_finished.reset();
+		for counter in self.ws_sessions_opened.iter().chain(&self.ws_sessions_closed) {
+			counter.reset();
+		}
+		self.requests_by_origin.reset();
+		self.methods_not_found.reset();
+	}
+}
+
+/// Map the `Origin` header to one of a few buckets, so that arbitrary client input can not blow up
+/// the cardinality of the metric labels.
+///
+/// The buckets are `none` if the header is absent, `null` for the literal `null` origin, `local`
+/// for loopback hosts, `external` for any other host and `invalid` if the header is malformed.
+fn origin_bucket(headers: &Headers) -> &'static str {
+	let origin = match headers.get("origin").map(|origin| origin.to_str()) {
+		None => return "none",
+		Some(Ok(origin)) => origin.trim(),
+		Some(Err(_)) => return "invalid",
+	};
+	if origin == "null" {
+		return "null"
+	}
+
+	let authority = match origin.split_once("://") {
+		Some((_, authority)) => authority,
+		None => return "invalid",
+	};
+	let host = match authority.strip_prefix('[') {
+		Some(ipv6) => ipv6.split(']').next().unwrap_or_default(),
+		None => authority.split(|c| c == ':' || c == '/').next().unwrap_or_default(),
+	};
+
+	let is_local = host.eq_ignore_ascii_case("localhost") ||
+		host.parse::<std::net::IpAddr>().map_or(false, |ip| ip.is_loopback());
+	if is_local {
+		"local"
+	} else if host.is_empty() {
+		"invalid"
+	} else {
+		"external"
+	}
+}
+
+/// Map a method name to its namespace if that is one of [`KNOWN_METHOD_PREFIXES`], else to
+/// `other`.
+fn method_prefix_bucket(name: &str) -> &'static str {
+	let prefix = name.split('_').next().unwrap_or_default();
+	KNOWN_METHOD_PREFIXES
+		.iter()
+		.find(|known| **known == prefix)
+		.copied()
+		.unwrap_or("other")
+}
+
+/// Receives the same lifecycle events of RPC calls as [`RpcMetrics`].
+///
+/// Implement this to feed RPC calls into a custom observability backend. All methods default to
+/// doing nothing.
+pub trait RpcLogger: Send + Sync + 'static {
+	/// Called when a new JSON-RPC request comes to the server.
+	fn on_request(&self, _transport_label: &'static str) {}
+
+	/// Called on each JSON-RPC method call, batch requests will trigger `on_call` multiple times.
+	fn on_call(
+		&self,
+		_transport_label: &'static str,
+		_name: &str,
+		_params: Params,
+		_kind: MethodKind,
+	) {
+	}
+
+	/// Called on each JSON-RPC method completion, batch requests will trigger `on_result` multiple
+	/// times.
+	fn on_result(
+		&self,
+		_transport_label: &'static str,
+		_name: &str,
+		_success: bool,
+		_started_at: Instant,
+	) {
+	}
+
+	/// Called once the JSON-RPC request is finished and response is sent to the output buffer.
+	fn on_response(&self, _transport_label: &'static str, _result: &str, _started_at: Instant) {}
+
+	/// Called when a new persistent connection is opened (Websocket only).
+	fn on_connect(&self, _remote_addr: SocketAddr) {}
+
+	/// Called when a persistent connection is closed (Websocket only).
+	fn on_disconnect(&self, _remote_addr: SocketAddr) {}
 }
 
 #[derive(Clone)]
 /// Middleware for RPC calls
 pub struct RpcMiddleware {
-	metrics: RpcMetrics,
+	metrics: Option<RpcMetrics>,
+	logger: Option<Arc<dyn RpcLogger>>,
 	transport_label: &'static str,
+	/// When each open persistent connection was established, by remote address.
+	connections: Arc<Mutex<HashMap<SocketAddr, Instant>>>,
 }
 
 impl RpcMiddleware {
 	/// Create a new [`RpcMiddleware`] with the provided [`RpcMetrics`].
 	pub fn new(metrics: RpcMetrics, transport_label: &'static str) -> Self {
-		Self { metrics, transport_label }
+		Self::with_logger(Some(metrics), None, transport_label)
+	}
+
+	/// Create a new [`RpcMiddleware`] with optional [`RpcMetrics`] and [`RpcLogger`].
+	pub fn with_logger(
+		metrics: Option<RpcMetrics>,
+		logger: Option<Arc<dyn RpcLogger>>,
+		transport_label: &'static str,
+	) -> Self {
+		Self { metrics, logger, transport_label, connections: Default::default() }
+	}
+
+	/// The ages of the open persistent connections by remote address, oldest first.
+	pub fn connection_ages(&self) -> Vec<(SocketAddr, Duration)> {
+		let mut ages: Vec<_> = self
+			.connections
+			.lock()
+			.expect("the connection registry is never poisoned; qed")
+			.iter()
+			.map(|(addr, established_at)| (*addr, established_at.elapsed()))
+			.collect();
+		ages.sort_by(|a, b| b.1.cmp(&a.1));
+		ages
 	}
 
 	/// Called when a new JSON-RPC request comes to the server.
 	fn on_request(&self) -> std::time::Instant {
 		let now = std::time::Instant::now();
-		self.metrics.requests_started.with_label_values(&[self.transport_label]).inc();
+		if let Some(metrics) = &self.metrics {
+			metrics.requests_started.with_label_values(&[self.transport_label]).inc();
+		}
+		if let Some(logger) = &self.logger {
+			logger.on_request(self.transport_label);
+		}
 		now
 	}
 
@@ -169,10 +363,18 @@ impl RpcMiddleware {
 			params,
 			kind,
 		);
-		self.metrics
-			.calls_started
-			.with_label_values(&[self.transport_label, name])
-			.inc();
+		if let Some(metrics) = &self.metrics {
+			metrics.calls_started.with_label_values(&[self.transport_label, name]).inc();
+			if matches!(kind, MethodKind::Unknown) {
+				metrics
+					.methods_not_found
+					.with_label_values(&[self.transport_label, method_prefix_bucket(name)])
+					.inc();
+			}
+		}
+		if let Some(logger) = &self.logger {
+			logger.on_call(self.transport_label, name, params, kind);
+		}
 	}
 
 	/// Called on each JSON-RPC method completion, batch requests will trigger `on_result` multiple
@@ -186,36 +388,64 @@ impl RpcMiddleware {
 			name,
 			micros,
 		);
-		self.metrics
-			.calls_time
-			.with_label_values(&[self.transport_label, name])
-			.observe(micros as _);
-
-		self.metrics
-			.calls_finished
-			.with_label_values(&[
-				self.transport_label,
-				name,
-				// the label "is_error", so `success` should be regarded as false
-				// and vice-versa to be registrered correctly.
-				if success { "false" } else { "true" },
-			])
-			.inc();
+		if let Some(metrics) = &self.metrics {
+			metrics
+				.calls_time
+				.with_label_values(&[self.transport_label, name])
+				.observe(micros as _);
+
+			metrics
+				.calls_finished
+				.with_label_values(&[
+					self.transport_label,
+					name,
+					// the label "is_error", so `success` should be regarded as false
+					// and vice-versa to be registrered correctly.
+					if success { "false" } else { "true" },
+				])
+				.inc();
+		}
+		if let Some(logger) = &self.logger {
+			logger.on_result(self.transport_label, name, success, started_at);
+		}
 	}
 
 	/// Called once the JSON-RPC request is finished and response is sent to the output buffer.
 	fn on_response(&self, result: &str, started_at: std::time::Instant) {
 		log::trace!(target: "rpc_metrics", "[{}] on_response started_at={:?}", self.transport_label, started_at);
 		log::trace!(target: "rpc_metrics::extra", "[{}] result={:?}", self.transport_label, result);
-		self.metrics.requests_finished.with_label_values(&[self.transport_label]).inc();
+		if let Some(metrics) = &self.metrics {
+			metrics.requests_finished.with_label_values(&[self.transport_label]).inc();
+		}
+		if let Some(logger) = &self.logger {
+			logger.on_response(self.transport_label, result, started_at);
+		}
 	}
 }
 
 impl WsMiddleware for RpcMiddleware {
 	type Instant = std::time::Instant;
 
-	fn on_connect(&self, _remote_addr: SocketAddr, _headers: &Headers) {
-		self.metrics.ws_sessions_opened.as_ref().map(|counter| counter.inc());
+	fn on_connect(&self, remote_addr: SocketAddr, headers: &Headers) {
+		let open = {
+			let mut connections =
+				self.connections.lock().expect("the connection registry is never poisoned; qed");
+			connections.insert(remote_addr, Instant::now());
+			connections.len()
+		};
+		if let Some(metrics) = &self.metrics {
+			metrics.ws_sessions_open.set(open as u64);
+			if let Some(counter) = &metrics.ws_sessions_opened {
+				counter.inc();
+			}
+			metrics
+				.requests_by_origin
+				.with_label_values(&[self.transport_label, origin_bucket(headers)])
+				.inc();
+		}
+		if let Some(logger) = &self.logger {
+			logger.on_connect(remote_addr);
+		}
 	}
 
 	fn on_request(&self) -> Self::Instant {
@@ -234,15 +464,35 @@ impl WsMiddleware for RpcMiddleware {
 		self.on_response(_result, started_at)
 	}
 
-	fn on_disconnect(&self, _remote_addr: SocketAddr) {
-		self.metrics.ws_sessions_closed.as_ref().map(|counter| counter.inc());
+	fn on_disconnect(&self, remote_addr: SocketAddr) {
+		let open = {
+			let mut connections =
+				self.connections.lock().expect("the connection registry is never poisoned; qed");
+			connections.remove(&remote_addr);
+			connections.len()
+		};
+		if let Some(metrics) = &self.metrics {
+			metrics.ws_sessions_open.set(open as u64);
+			if let Some(counter) = &metrics.ws_sessions_closed {
+				counter.inc();
+			}
+		}
+		if let Some(logger) = &self.logger {
+			logger.on_disconnect(remote_addr);
+		}
 	}
 }
 
 impl HttpMiddleware for RpcMiddleware {
 	type Instant = std::time::Instant;
 
-	fn on_request(&self, _remote_addr: SocketAddr, _headers: &Headers) -> Self::Instant {
+	fn on_request(&self, _remote_addr: SocketAddr, headers: &Headers) -> Self::Instant {
+		if let Some(metrics) = &self.metrics {
+			metrics
+				.requests_by_origin
+				.with_label_values(&[self.transport_label, origin_bucket(headers)])
+				.inc();
+		}
 		self.on_request()
 	}
 
@@ -258,3 +508,147 @@ impl HttpMiddleware for RpcMiddleware {
 		self.on_response(_result, started_at)
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::Mutex;
+
+	#[derive(Default)]
+	struct MockLogger(Mutex<Vec<String>>);
+
+	impl RpcLogger for MockLogger {
+		fn on_request(&self, transport_label: &'static str) {
+			self.0.lock().unwrap().push(format!("request {}", transport_label));
+		}
+
+		fn on_call(&self, _transport_label: &'static str, name: &str, _: Params, _: MethodKind) {
+			self.0.lock().unwrap().push(format!("call {}", name));
+		}
+
+		fn on_result(&self, _: &'static str, name: &str, success: bool, _: Instant) {
+			self.0.lock().unwrap().push(format!("result {} {}", name, success));
+		}
+
+		fn on_response(&self, _: &'static str, _: &str, _: Instant) {
+			self.0.lock().unwrap().push("response".into());
+		}
+	}
+
+	#[test]
+	fn logger_receives_lifecycle_events() {
+		let logger = Arc::new(MockLogger::default());
+		let middleware = RpcMiddleware::with_logger(None, Some(logger.clone()), "http");
+
+		let started_at =
+			HttpMiddleware::on_request(&middleware, ([127, 0, 0, 1], 9933).into(), &Headers::new());
+		let params = Params::new(None);
+		HttpMiddleware::on_call(&middleware, "system_name", params, MethodKind::MethodCall);
+		HttpMiddleware::on_result(&middleware, "system_name", true, started_at);
+		HttpMiddleware::on_response(&middleware, "{}", started_at);
+
+		assert_eq!(
+			*logger.0.lock().unwrap(),
+			vec!["request http", "call system_name", "result system_name true", "response"],
+		);
+	}
+
+	#[cfg(all(feature = "test-helpers", debug_assertions))]
+	#[test]
+	fn reset_zeroes_metrics() {
+		let registry = Registry::new();
+		let metrics = RpcMetrics::new(Some(&registry)).unwrap().unwrap();
+		let middleware = RpcMiddleware::new(metrics.clone(), "ws");
+
+		WsMiddleware::on_request(&middleware);
+		WsMiddleware::on_connect(&middleware, ([127, 0, 0, 1], 9944).into(), &Headers::new());
+		assert_eq!(metrics.requests_started.with_label_values(&["ws"]).get(), 1);
+		assert_eq!(metrics.ws_sessions_opened.as_ref().unwrap().get(), 1);
+
+		metrics.reset();
+		assert_eq!(metrics.requests_started.with_label_values(&["ws"]).get(), 0);
+		assert_eq!(metrics.ws_sessions_opened.as_ref().unwrap().get(), 0);
+	}
+
+	#[test]
+	fn open_connections_are_tracked() {
+		let registry = Registry::new();
+		let metrics = RpcMetrics::new(Some(&registry)).unwrap().unwrap();
+		let middleware = RpcMiddleware::new(metrics.clone(), "ws");
+		let first = ([127, 0, 0, 1], 40001).into();
+		let second = ([127, 0, 0, 1], 40002).into();
+
+		WsMiddleware::on_connect(&middleware, first, &Headers::new());
+		std::thread::sleep(Duration::from_millis(20));
+		WsMiddleware::on_connect(&middleware, second, &Headers::new());
+		assert_eq!(metrics.ws_sessions_open.get(), 2);
+
+		let ages = middleware.connection_ages();
+		assert_eq!(ages.iter().map(|(addr, _)| *addr).collect::<Vec<_>>(), vec![first, second]);
+		assert!(ages[0].1 >= Duration::from_millis(20));
+
+		WsMiddleware::on_disconnect(&middleware, first);
+		assert_eq!(metrics.ws_sessions_open.get(), 1);
+		assert_eq!(middleware.connection_ages().len(), 1);
+	}
+
+	#[test]
+	fn origins_are_bucketed() {
+		let bucket = |origin: Option<&'static str>| {
+			let mut headers = Headers::new();
+			if let Some(origin) = origin {
+				headers.insert("origin", origin.parse().unwrap());
+			}
+			origin_bucket(&headers)
+		};
+
+		assert_eq!(bucket(None), "none");
+		assert_eq!(bucket(Some("null")), "null");
+		assert_eq!(bucket(Some("http://localhost:3000")), "local");
+		assert_eq!(bucket(Some("http://127.0.0.1")), "local");
+		assert_eq!(bucket(Some("http://[::1]:9933")), "local");
+		assert_eq!(bucket(Some("https://polkadot.js.org")), "external");
+		assert_eq!(bucket(Some("localhost")), "invalid");
+	}
+
+	#[test]
+	fn requests_by_origin_are_counted() {
+		let registry = Registry::new();
+		let metrics = RpcMetrics::new(Some(&registry)).unwrap().unwrap();
+		let middleware = RpcMiddleware::new(metrics.clone(), "http");
+		let addr = ([127, 0, 0, 1], 9933).into();
+
+		let mut headers = Headers::new();
+		HttpMiddleware::on_request(&middleware, addr, &headers);
+		headers.insert("origin", "https://polkadot.js.org".parse().unwrap());
+		HttpMiddleware::on_request(&middleware, addr, &headers);
+		HttpMiddleware::on_request(&middleware, addr, &headers);
+
+		let count = |bucket| metrics.requests_by_origin.with_label_values(&["http", bucket]).get();
+		assert_eq!(count("none"), 1);
+		assert_eq!(count("external"), 2);
+		assert_eq!(count("local"), 0);
+	}
+
+	#[test]
+	fn unknown_methods_are_counted_by_prefix() {
+		let registry = Registry::new();
+		let metrics = RpcMetrics::new(Some(&registry)).unwrap().unwrap();
+		let middleware = RpcMiddleware::new(metrics.clone(), "ws");
+
+		let call = |name| {
+			WsMiddleware::on_call(&middleware, name, Params::new(None), MethodKind::Unknown)
+		};
+		call("state_getStorageOld");
+		call("state_queryStorageOld");
+		call("author_submitOld");
+		call("totally_random_name");
+		WsMiddleware::on_call(&middleware, "system_name", Params::new(None), MethodKind::MethodCall);
+
+		let count = |prefix| metrics.methods_not_found.with_label_values(&["ws", prefix]).get();
+		assert_eq!(count("state"), 2);
+		assert_eq!(count("author"), 1);
+		assert_eq!(count("other"), 1);
+		assert_eq!(count("system"), 0);
+	}
+}
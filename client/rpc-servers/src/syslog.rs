@@ -0,0 +1,159 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2022 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! An [`RpcLogger`] that writes an access log to the local syslog.
+
+use std::{os::unix::net::UnixDatagram, path::Path, time::Instant};
+
+use crate::RpcLogger;
+
+/// The default path of the local syslog socket.
+pub const SYSLOG_PATH: &str = "/dev/log";
+
+/// Syslog facility of the access log records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyslogFacility {
+	/// Generic user-level messages.
+	User,
+	/// System daemons.
+	Daemon,
+	/// Locally used facility 0.
+	Local0,
+	/// Locally used facility 1.
+	Local1,
+	/// Locally used facility 2.
+	Local2,
+	/// Locally used facility 3.
+	Local3,
+	/// Locally used facility 4.
+	Local4,
+	/// Locally used facility 5.
+	Local5,
+	/// Locally used facility 6.
+	Local6,
+	/// Locally used facility 7.
+	Local7,
+}
+
+impl SyslogFacility {
+	fn code(self) -> u8 {
+		match self {
+			Self::User => 1,
+			Self::Daemon => 3,
+			Self::Local0 => 16,
+			Self::Local1 => 17,
+			Self::Local2 => 18,
+			Self::Local3 => 19,
+			Self::Local4 => 20,
+			Self::Local5 => 21,
+			Self::Local6 => 22,
+			Self::Local7 => 23,
+		}
+	}
+}
+
+/// Writes one record per completed RPC call to the local syslog.
+///
+/// If the syslog socket cannot be connected to, or a record cannot be sent, records are written
+/// to the standard error instead.
+pub struct SyslogLogger {
+	socket: Option<UnixDatagram>,
+	facility: SyslogFacility,
+}
+
+impl SyslogLogger {
+	/// Create a new [`SyslogLogger`] writing to [`SYSLOG_PATH`].
+	pub fn new(facility: SyslogFacility) -> Self {
+		Self::with_path(SYSLOG_PATH, facility)
+	}
+
+	/// Create a new [`SyslogLogger`] writing to the syslog socket at `path`.
+	pub fn with_path(path: impl AsRef<Path>, facility: SyslogFacility) -> Self {
+		let path = path.as_ref();
+		let socket = UnixDatagram::unbound().and_then(|socket| {
+			socket.connect(path)?;
+			Ok(socket)
+		});
+		let socket = match socket {
+			Ok(socket) => Some(socket),
+			Err(e) => {
+				log::warn!(
+					"Cannot connect to syslog at {}: {}, writing the RPC access log to stderr",
+					path.display(),
+					e,
+				);
+				None
+			},
+		};
+		Self { socket, facility }
+	}
+
+	fn write(&self, message: &str) {
+		// Severity 6 is informational.
+		let record = format!("<{}>substrate-rpc: {}", self.facility.code() * 8 + 6, message);
+		let sent = self.socket.as_ref().map_or(false, |s| s.send(record.as_bytes()).is_ok());
+		if !sent {
+			eprintln!("{}", message);
+		}
+	}
+}
+
+impl RpcLogger for SyslogLogger {
+	fn on_result(
+		&self,
+		transport_label: &'static str,
+		name: &str,
+		success: bool,
+		started_at: Instant,
+	) {
+		self.write(&format!(
+			"{} {} {} {}us",
+			transport_label,
+			name,
+			if success { "ok" } else { "error" },
+			started_at.elapsed().as_micros(),
+		));
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn records_are_sent_to_syslog() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("syslog");
+		let receiver = UnixDatagram::bind(&path).unwrap();
+
+		let logger = SyslogLogger::with_path(&path, SyslogFacility::Local3);
+		logger.on_result("http", "system_health", true, Instant::now());
+
+		let mut buf = [0; 1024];
+		let len = receiver.recv(&mut buf).unwrap();
+		let record = std::str::from_utf8(&buf[..len]).unwrap();
+		assert!(record.starts_with("<158>substrate-rpc: http system_health ok "), "{}", record);
+	}
+
+	#[test]
+	fn missing_syslog_falls_back_to_stderr() {
+		let logger = SyslogLogger::with_path("/nonexistent/syslog", SyslogFacility::User);
+		assert!(logger.socket.is_none());
+		logger.on_result("ws", "system_health", false, Instant::now());
+	}
+}
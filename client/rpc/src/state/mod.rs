@@ -163,11 +163,16 @@ where
 }
 
 /// Create new state API that works on full node.
+///
+/// Storage subscriptions with more than `max_subscription_keys` keys are rejected with an error,
+/// well before they reach the request size limit of the server, which would otherwise reject the
+/// whole request. `None` if unlimited.
 pub fn new_full<BE, Block: BlockT, Client>(
 	client: Arc<Client>,
 	executor: SubscriptionTaskExecutor,
 	deny_unsafe: DenyUnsafe,
 	rpc_max_payload: Option<usize>,
+	max_subscription_keys: Option<usize>,
 ) -> (State<Block, Client>, ChildState<Block, Client>)
 where
 	Block: BlockT + 'static,
@@ -193,7 +198,7 @@ where
 		rpc_max_payload,
 	));
 	let backend = Box::new(self::state_full::FullState::new(client, executor, rpc_max_payload));
-	(State { backend, deny_unsafe }, ChildState { backend: child_backend })
+	(State { backend, deny_unsafe, max_subscription_keys }, ChildState { backend: child_backend })
 }
 
 /// State API with subscriptions support.
@@ -201,6 +206,8 @@ pub struct State<Block, Client> {
 	backend: Box<dyn StateBackend<Block, Client>>,
 	/// Whether to deny unsafe calls
 	deny_unsafe: DenyUnsafe,
+	/// Maximum number of keys of a single storage subscription. `None` if unlimited.
+	max_subscription_keys: Option<usize>,
 }
 
 impl<Block, Client> StateApiServer<Block::Hash> for State<Block, Client>
@@ -329,11 +336,20 @@ where
 		mut sink: SubscriptionSink,
 		keys: Option<Vec<StorageKey>>,
 	) -> SubscriptionResult {
-		if keys.is_none() {
-			if let Err(err) = self.deny_unsafe.check_if_safe() {
-				let _ = sink.reject(JsonRpseeError::from(err));
+		match (&keys, self.max_subscription_keys) {
+			(None, _) =>
+				if let Err(err) = self.deny_unsafe.check_if_safe() {
+					let _ = sink.reject(JsonRpseeError::from(err));
+					return Ok(())
+				},
+			(Some(keys), Some(max)) if keys.len() > max => {
+				let _ = sink.reject(JsonRpseeError::from(Error::TooManySubscriptionKeys {
+					count: keys.len(),
+					max,
+				}));
 				return Ok(())
-			}
+			},
+			(Some(_), _) => {},
 		}
 
 		self.backend.subscribe_storage(sink, keys);
@@ -661,6 +661,7 @@ where
 			task_executor.clone(),
 			deny_unsafe,
 			config.rpc_max_payload,
+			config.rpc_max_subscription_keys,
 		);
 		let state = state.into_rpc();
 		let child_state = child_state.into_rpc();
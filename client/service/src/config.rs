@@ -111,6 +111,12 @@ pub struct Configuration {
 	///
 	/// Default: 1024.
 	pub rpc_max_subs_per_conn: Option<usize>,
+	/// Maximum number of keys of a single storage subscription. `None` if unlimited.
+	pub rpc_max_subscription_keys: Option<usize>,
+	/// Maximum number of RPC methods that may be exposed. `None` if unlimited.
+	pub rpc_max_exposed_methods: Option<usize>,
+	/// Path of the syslog socket the RPC access log is written to. `None` if disabled.
+	pub rpc_log_syslog: Option<PathBuf>,
 	/// Maximum size of the output buffer capacity for websocket connections.
 	pub ws_max_out_buffer_capacity: Option<usize>,
 	/// Prometheus endpoint configuration. `None` if disabled.
@@ -300,7 +300,7 @@ mod waiting {
 		fn drop(&mut self) {
 			if let Some(server) = self.0.take() {
 				// This doesn't not wait for the server to be stopped but fires the signal.
-				let _ = server.stop();
+				let _ = server.into_inner().stop();
 			}
 		}
 	}
@@ -311,7 +311,7 @@ mod waiting {
 		fn drop(&mut self) {
 			if let Some(server) = self.0.take() {
 				// This doesn't not wait for the server to be stopped but fires the signal.
-				let _ = server.stop();
+				let _ = server.into_inner().stop();
 			}
 		}
 	}
@@ -353,12 +353,28 @@ where
 
 	let metrics = sc_rpc_server::RpcMetrics::new(config.prometheus_registry())?;
 
+	let rpc_logger = || -> Option<Box<dyn sc_rpc_server::RpcLogger>> {
+		let path = config.rpc_log_syslog.as_ref()?;
+		#[cfg(unix)]
+		{
+			let facility = sc_rpc_server::SyslogFacility::Daemon;
+			Some(Box::new(sc_rpc_server::SyslogLogger::with_path(path, facility)))
+		}
+		#[cfg(not(unix))]
+		{
+			warn!("Syslog is only supported on Unix, not writing the RPC access log to {:?}", path);
+			None
+		}
+	};
+
 	let http_fut = sc_rpc_server::start_http(
 		[http_addr, http_addr2],
 		config.rpc_cors.as_ref(),
 		max_request_size,
 		http_max_response_size,
+		config.rpc_max_exposed_methods,
 		metrics.clone(),
+		rpc_logger(),
 		gen_rpc_module(deny_unsafe(ws_addr, &config.rpc_methods))?,
 		config.tokio_handle.clone(),
 	);
@@ -368,6 +384,7 @@ where
 		max_payload_in_mb: max_request_size,
 		max_payload_out_mb: ws_max_response_size,
 		max_subs_per_conn: config.rpc_max_subs_per_conn,
+		max_exposed_methods: config.rpc_max_exposed_methods,
 	};
 
 	let ws_fut = sc_rpc_server::start_ws(
@@ -375,6 +392,7 @@ where
 		config.rpc_cors.as_ref(),
 		ws_config,
 		metrics,
+		rpc_logger(),
 		gen_rpc_module(deny_unsafe(http_addr, &config.rpc_methods))?,
 		config.tokio_handle.clone(),
 		rpc_id_provider,
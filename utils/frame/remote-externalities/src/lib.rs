@@ -25,7 +25,7 @@ use codec::{Decode, Encode};
 use log::*;
 use serde::de::DeserializeOwned;
 use sp_core::{
-	hashing::twox_128,
+	hashing::{blake2_256, twox_128},
 	hexdisplay::HexDisplay,
 	storage::{
 		well_known_keys::{is_default_child_storage_key, DEFAULT_CHILD_STORAGE_KEY_PREFIX},
@@ -49,6 +49,13 @@ const LOG_TARGET: &str = "remote-ext";
 const DEFAULT_TARGET: &str = "wss://rpc.polkadot.io:443";
 const BATCH_SIZE: usize = 1000;
 const PAGE: u32 = 1000;
+/// Header of snapshot files that end with a checksum footer.
+///
+/// Older snapshot files are the bare encoded key-values. Those only start with a zero byte if they
+/// are the single byte encoding of no key-values, so they can not be mistaken for this header.
+const SNAPSHOT_MAGIC: &[u8] = b"\0snapshot-v1";
+/// Length of the checksum footer appended to every snapshot file.
+const SNAPSHOT_FOOTER_LEN: usize = 32;
 
 /// The execution mode.
 #[derive(Clone)]
@@ -137,6 +144,8 @@ pub struct OnlineConfig<B: BlockT> {
 	/// Transport config.
 	pub transport: Transport,
 	/// Lookout for child-keys, and scrape them as well if set to true.
+	///
+	/// If false, the child tries are neither fetched nor written to the state snapshot.
 	pub scrape_children: bool,
 }
 
@@ -468,19 +477,71 @@ where
 	B::Hash: DeserializeOwned,
 	B::Header: DeserializeOwned,
 {
-	/// Save the given data to the top keys snapshot.
-	fn save_top_snapshot(&self, data: &[KeyValue], path: &PathBuf) -> Result<(), &'static str> {
-		let mut path = path.clone();
-		let encoded = data.encode();
-		path.set_extension("top");
+	/// Write `data` to `path`, after a [`SNAPSHOT_MAGIC`] header and followed by a checksum
+	/// footer.
+	///
+	/// The data is first written to a temporary sibling file which is then renamed over `path`,
+	/// so an interrupted write never leaves a partial snapshot behind.
+	fn write_snapshot_file(data: &[u8], path: &Path) -> Result<(), &'static str> {
+		let mut tmp = path.as_os_str().to_owned();
+		tmp.push(".tmp");
+		let tmp = PathBuf::from(tmp);
+
+		let mut bytes =
+			Vec::with_capacity(SNAPSHOT_MAGIC.len() + data.len() + SNAPSHOT_FOOTER_LEN);
+		bytes.extend_from_slice(SNAPSHOT_MAGIC);
+		bytes.extend_from_slice(data);
+		bytes.extend_from_slice(&blake2_256(data));
+
 		debug!(
 			target: LOG_TARGET,
 			"writing {} bytes to state snapshot file {:?}",
-			encoded.len(),
+			bytes.len(),
 			path
 		);
-		fs::write(path, encoded).map_err(|_| "fs::write failed.")?;
-		Ok(())
+		let written = fs::write(&tmp, bytes)
+			.map_err(|_| "fs::write failed.")
+			.and_then(|_| fs::rename(&tmp, path).map_err(|_| "fs::rename failed."));
+		if written.is_err() {
+			let _ = fs::remove_file(&tmp);
+		}
+		written
+	}
+
+	/// Read a snapshot file written by [`Self::write_snapshot_file`], verifying its checksum.
+	///
+	/// Files without the [`SNAPSHOT_MAGIC`] header are read as they are, since snapshots written
+	/// before the header was introduced have no checksum.
+	fn read_snapshot_file(path: &Path) -> Result<Vec<u8>, &'static str> {
+		let bytes = fs::read(path).map_err(|_| "fs::read failed.")?;
+		let mut bytes = match bytes.strip_prefix(SNAPSHOT_MAGIC) {
+			Some(bytes) => bytes.to_vec(),
+			None => {
+				log::warn!(
+					target: LOG_TARGET,
+					"snapshot file {:?} has no checksum, it can not be checked for corruption",
+					path
+				);
+				return Ok(bytes)
+			},
+		};
+		if bytes.len() < SNAPSHOT_FOOTER_LEN {
+			log::error!(target: LOG_TARGET, "snapshot file {:?} is too short", path);
+			return Err("snapshot is truncated or corrupted")
+		}
+		let footer = bytes.split_off(bytes.len() - SNAPSHOT_FOOTER_LEN);
+		if blake2_256(&bytes)[..] != footer[..] {
+			log::error!(target: LOG_TARGET, "checksum mismatch in snapshot file {:?}", path);
+			return Err("snapshot is truncated or corrupted")
+		}
+		Ok(bytes)
+	}
+
+	/// Save the given data to the top keys snapshot.
+	fn save_top_snapshot(&self, data: &[KeyValue], path: &PathBuf) -> Result<(), &'static str> {
+		let mut path = path.clone();
+		path.set_extension("top");
+		Self::write_snapshot_file(&data.encode(), &path)
 	}
 
 	/// Save the given data to the child keys snapshot.
@@ -491,22 +552,14 @@ where
 	) -> Result<(), &'static str> {
 		let mut path = path.clone();
 		path.set_extension("child");
-		let encoded = data.encode();
-		debug!(
-			target: LOG_TARGET,
-			"writing {} bytes to state snapshot file {:?}",
-			encoded.len(),
-			path
-		);
-		fs::write(path, encoded).map_err(|_| "fs::write failed.")?;
-		Ok(())
+		Self::write_snapshot_file(&data.encode(), &path)
 	}
 
 	fn load_top_snapshot(&self, path: &PathBuf) -> Result<TopKeyValues, &'static str> {
 		let mut path = path.clone();
 		path.set_extension("top");
 		info!(target: LOG_TARGET, "loading top key-pairs from snapshot {:?}", path);
-		let bytes = fs::read(path).map_err(|_| "fs::read failed.")?;
+		let bytes = Self::read_snapshot_file(&path)?;
 		Decode::decode(&mut &*bytes).map_err(|e| {
 			log::error!(target: LOG_TARGET, "{:?}", e);
 			"decode failed"
@@ -517,7 +570,7 @@ where
 		let mut path = path.clone();
 		path.set_extension("child");
 		info!(target: LOG_TARGET, "loading child key-pairs from snapshot {:?}", path);
-		let bytes = fs::read(path).map_err(|_| "fs::read failed.")?;
+		let bytes = Self::read_snapshot_file(&path)?;
 		Decode::decode(&mut &*bytes).map_err(|e| {
 			log::error!(target: LOG_TARGET, "{:?}", e);
 			"decode failed"
@@ -541,6 +594,10 @@ where
 		&self,
 		top_kv: &[KeyValue],
 	) -> Result<ChildKeyValues, &'static str> {
+		if !self.as_online().scrape_children {
+			info!(target: LOG_TARGET, "skipping child-tree data, as configured");
+			return Ok(Default::default())
+		}
 		let child_kv = self.load_child_remote(top_kv).await?;
 		if let Some(c) = &self.as_online().state_snapshot {
 			self.save_child_snapshot(&child_kv, &c.path)?;
@@ -877,6 +934,24 @@ mod tests {
 			.execute_with(|| {});
 	}
 
+	#[tokio::test]
+	async fn child_trees_are_not_fetched_if_disabled() {
+		init_logger();
+		let builder = Builder::<Block>::new().mode(Mode::Online(OnlineConfig {
+			transport: "ws://127.0.0.1:1".to_owned().into(),
+			scrape_children: false,
+			..Default::default()
+		}));
+		let child_root = StorageKey([DEFAULT_CHILD_STORAGE_KEY_PREFIX, b"child"].concat());
+
+		// the remote client is not initialized, so any fetch would panic.
+		let child_kv = builder
+			.load_child_remote_and_maybe_save(&[(child_root, StorageData(vec![1; 32]))])
+			.await
+			.unwrap();
+		assert!(child_kv.is_empty());
+	}
+
 	#[tokio::test]
 	async fn can_exclude_from_cache() {
 		init_logger();
@@ -906,6 +981,52 @@ mod tests {
 			.expect("Can't read state snapshot file")
 			.execute_with(|| assert!(sp_io::storage::get(&some_key).is_none()));
 	}
+
+	#[test]
+	fn snapshot_without_checksum_is_loaded() {
+		init_logger();
+		let path = PathBuf::from("test_data/proxy_test");
+		assert!(!fs::read(path.with_extension("top")).unwrap().starts_with(SNAPSHOT_MAGIC));
+
+		let data = Builder::<Block>::new().load_top_snapshot(&path).unwrap();
+		assert!(!data.is_empty());
+	}
+
+	#[test]
+	fn failed_snapshot_write_leaves_no_temporary_file() {
+		init_logger();
+		let dir = tempfile::tempdir().unwrap();
+		// a snapshot can not be renamed over a directory.
+		let path = dir.path().join("snapshot.top");
+		fs::create_dir(&path).unwrap();
+		fs::write(path.join("file"), b"").unwrap();
+
+		assert_eq!(
+			Builder::<Block>::write_snapshot_file(b"data", &path).unwrap_err(),
+			"fs::rename failed."
+		);
+		assert!(!dir.path().join("snapshot.top.tmp").exists());
+	}
+
+	#[test]
+	fn truncated_snapshot_is_detected() {
+		init_logger();
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("snapshot");
+		let builder = Builder::<Block>::new();
+		let data = vec![(StorageKey(vec![1, 2, 3]), StorageData(vec![42; 64]))];
+
+		builder.save_top_snapshot(&data, &path).unwrap();
+		assert_eq!(builder.load_top_snapshot(&path).unwrap(), data);
+
+		let file = path.with_extension("top");
+		let len = fs::metadata(&file).unwrap().len();
+		fs::OpenOptions::new().write(true).open(&file).unwrap().set_len(len - 10).unwrap();
+		assert_eq!(
+			builder.load_top_snapshot(&path).unwrap_err(),
+			"snapshot is truncated or corrupted"
+		);
+	}
 }
 
 #[cfg(all(test, feature = "remote-test"))]
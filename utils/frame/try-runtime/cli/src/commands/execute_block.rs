@@ -212,6 +212,8 @@ where
 		"TryRuntime_execute_block",
 		&payload,
 		full_extensions(),
+		None,
+		None,
 	)?;
 
 	log::info!(target: LOG_TARGET, "Core_execute_block executed without errors.");
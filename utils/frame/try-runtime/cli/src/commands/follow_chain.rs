@@ -26,7 +26,8 @@ use sc_service::Configuration;
 use serde::{de::DeserializeOwned, Serialize};
 use sp_core::H256;
 use sp_runtime::traits::{Block as BlockT, Header as HeaderT, NumberFor};
-use std::{fmt::Debug, str::FromStr};
+use sp_weights::Weight;
+use std::{fmt::Debug, path::PathBuf, str::FromStr};
 use substrate_rpc_client::{ws_client, ChainApi, FinalizedHeaders, Subscription, WsClient};
 
 const SUB: &str = "chain_subscribeFinalizedHeads";
@@ -58,6 +59,100 @@ pub struct FollowChainCmd {
 	/// If present, a single connection to a node will be kept and reused for fetching blocks.
 	#[arg(long)]
 	keep_connection: bool,
+
+	/// Write the [`MultiBlockSummary`] of the executed blocks to this path, as JSON.
+	///
+	/// The file is rewritten after each block, so that it is up to date if the command is
+	/// interrupted.
+	#[arg(long, value_name = "PATH")]
+	summary: Option<PathBuf>,
+}
+
+/// Aggregated statistics over all the blocks executed by [`follow_chain`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MultiBlockSummary<Number> {
+	/// The number of blocks executed.
+	pub total_blocks: u64,
+	/// The component-wise minimum weight consumed by a block.
+	pub min_weight: Weight,
+	/// The component-wise maximum weight consumed by a block.
+	pub max_weight: Weight,
+	/// The component-wise mean weight consumed by a block.
+	pub mean_weight: Weight,
+	/// The number and weight of the block that consumed the most `ref_time`, if any.
+	pub worst_block: Option<(Number, Weight)>,
+	/// The total weight consumed by all blocks.
+	pub total_weight: Weight,
+}
+
+impl<Number> Default for MultiBlockSummary<Number> {
+	fn default() -> Self {
+		Self {
+			total_blocks: 0,
+			min_weight: Weight::zero(),
+			max_weight: Weight::zero(),
+			mean_weight: Weight::zero(),
+			worst_block: None,
+			total_weight: Weight::zero(),
+		}
+	}
+}
+
+impl<Number: Debug> MultiBlockSummary<Number> {
+	/// Account for block `number` having consumed `weight`.
+	pub fn note_block(&mut self, number: Number, weight: Weight) {
+		if self.total_blocks == 0 {
+			self.min_weight = weight;
+		} else {
+			self.min_weight = self.min_weight.min(weight);
+		}
+		self.max_weight = self.max_weight.max(weight);
+		self.total_blocks += 1;
+		self.total_weight.saturating_accrue(weight);
+		self.mean_weight = self.total_weight.saturating_div(self.total_blocks);
+
+		if self
+			.worst_block
+			.as_ref()
+			.map_or(true, |(_, worst)| weight.ref_time() > worst.ref_time())
+		{
+			self.worst_block = Some((number, weight));
+		}
+	}
+
+	/// The summary as a JSON object, with weights as `ref_time`/`proof_size` pairs.
+	pub fn to_json(&self) -> serde_json::Value
+	where
+		Number: Serialize,
+	{
+		let weight = |weight: Weight| {
+			serde_json::json!({ "ref_time": weight.ref_time(), "proof_size": weight.proof_size() })
+		};
+		serde_json::json!({
+			"total_blocks": self.total_blocks,
+			"min_weight": weight(self.min_weight),
+			"max_weight": weight(self.max_weight),
+			"mean_weight": weight(self.mean_weight),
+			"total_weight": weight(self.total_weight),
+			"worst_block": self.worst_block.as_ref().map(|(number, worst)| {
+				serde_json::json!({ "number": number, "weight": weight(*worst) })
+			}),
+		})
+	}
+
+	/// Print the summary with [`LOG_TARGET`].
+	fn log(&self) {
+		log::info!(
+			target: LOG_TARGET,
+			"executed {} blocks, weight min {}, max {}, mean {}, total {}, worst block {:?}",
+			self.total_blocks,
+			self.min_weight,
+			self.max_weight,
+			self.mean_weight,
+			self.total_weight,
+			self.worst_block,
+		);
+	}
 }
 
 /// Start listening for with `SUB` at `url`.
@@ -81,7 +176,7 @@ pub(crate) async fn follow_chain<Block, ExecDispatch>(
 	shared: SharedParams,
 	command: FollowChainCmd,
 	config: Configuration,
-) -> sc_cli::Result<()>
+) -> sc_cli::Result<MultiBlockSummary<NumberFor<Block>>>
 where
 	Block: BlockT<Hash = H256> + DeserializeOwned,
 	Block::Hash: FromStr,
@@ -92,6 +187,7 @@ where
 	ExecDispatch: NativeExecutionDispatch + 'static,
 {
 	let mut maybe_state_ext = None;
+	let mut summary = MultiBlockSummary::default();
 	let (rpc, subscription) = start_subscribing::<Block::Header>(&command.uri).await?;
 
 	let (code_key, code) = extract_code(&config.chain_spec)?;
@@ -155,13 +251,15 @@ where
 		let (state_ext, spec_state_version) =
 			maybe_state_ext.as_mut().expect("state_ext either existed or was just created");
 
-		let (mut changes, encoded_result) = state_machine_call_with_proof::<Block, ExecDispatch>(
+		let (mut changes, encoded_result, _) = state_machine_call_with_proof::<Block, ExecDispatch>(
 			state_ext,
 			&executor,
 			execution,
 			"TryRuntime_execute_block",
 			(block, command.state_root_check, command.try_state.clone()).encode().as_ref(),
 			full_extensions(),
+			None,
+			None,
 		)?;
 
 		let consumed_weight = <sp_weights::Weight as Decode>::decode(&mut &*encoded_result)
@@ -190,8 +288,54 @@ where
 			consumed_weight,
 			state_ext.as_backend().root(),
 		);
+		summary.note_block(*number, consumed_weight);
+		if let Some(path) = &command.summary {
+			std::fs::write(path, summary.to_json().to_string())?;
+		}
 	}
 
 	log::error!(target: LOG_TARGET, "ws subscription must have terminated.");
-	Ok(())
+	summary.log();
+	Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn summary_aggregates_blocks() {
+		let mut summary = MultiBlockSummary::<u32>::default();
+		assert_eq!(summary.worst_block, None);
+
+		summary.note_block(1, Weight::from_parts(20, 5));
+		summary.note_block(2, Weight::from_parts(50, 1));
+		summary.note_block(3, Weight::from_parts(30, 3));
+
+		assert_eq!(summary.total_blocks, 3);
+		assert_eq!(summary.min_weight, Weight::from_parts(20, 1));
+		assert_eq!(summary.max_weight, Weight::from_parts(50, 5));
+		assert_eq!(summary.mean_weight, Weight::from_parts(33, 3));
+		assert_eq!(summary.total_weight, Weight::from_parts(100, 9));
+		assert_eq!(summary.worst_block, Some((2, Weight::from_parts(50, 1))));
+
+		assert_eq!(
+			summary.to_json(),
+			serde_json::json!({
+				"total_blocks": 3,
+				"min_weight": { "ref_time": 20, "proof_size": 1 },
+				"max_weight": { "ref_time": 50, "proof_size": 5 },
+				"mean_weight": { "ref_time": 33, "proof_size": 3 },
+				"total_weight": { "ref_time": 100, "proof_size": 9 },
+				"worst_block": { "number": 2, "weight": { "ref_time": 50, "proof_size": 1 } },
+			}),
+		);
+	}
+
+	#[test]
+	fn empty_summary_has_no_worst_block() {
+		let summary = MultiBlockSummary::<u32>::default();
+		assert_eq!(summary.to_json()["total_blocks"], 0);
+		assert!(summary.to_json()["worst_block"].is_null());
+	}
 }
@@ -15,17 +15,51 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{fmt::Debug, str::FromStr};
+use std::{
+	fmt::Debug,
+	path::{Path, PathBuf},
+	str::FromStr,
+	sync::Arc,
+	time::{Duration, Instant},
+};
 
-use parity_scale_codec::Decode;
-use sc_executor::NativeExecutionDispatch;
+use parity_scale_codec::{Compact, Decode, Encode};
+use remote_externalities::TestExternalities;
+use sc_chain_spec::ChainSpec;
+use sc_cli::execution_method_from_cli;
+use sc_executor::{
+	sp_wasm_interface::ExtendedHostFunctions, AllocationStats, NativeElseWasmExecutor,
+	NativeExecutionDispatch, WasmExecutor,
+};
 use sc_service::Configuration;
-use sp_runtime::traits::{Block as BlockT, NumberFor};
+use sp_core::{
+	hexdisplay::HexDisplay,
+	offchain::{testing::TestOffchainExt, OffchainDbExt, OffchainWorkerExt, Timestamp},
+	storage::{well_known_keys, ChildInfo, Storage, StorageChild, StorageData, StorageKey},
+	testing::TaskExecutor,
+	traits::{FetchRuntimeCode, TaskExecutorExt},
+	twox_128,
+};
+use sp_externalities::Extensions;
+use sp_keystore::{testing::KeyStore, KeystoreExt};
+use sp_runtime::traits::{Block as BlockT, Header as HeaderT, NumberFor};
+use sp_state_machine::{
+	backend::BackendRuntimeCode, Backend, Ext, OverlayedChanges, StorageTransactionCache,
+	StorageValue,
+};
+use sp_version::StateVersion;
 use sp_weights::Weight;
 
 use crate::{
-	build_executor, ensure_matching_spec, extract_code, local_spec, state_machine_call_with_proof,
-	SharedParams, State, LOG_TARGET,
+	build_executor,
+	db_stats::DbOpStats,
+	ensure_matching_spec,
+	events::{EventDecoder, EventRecord},
+	extract_code,
+	folded_stacks::FoldedStacks,
+	hash_of, local_spec, parse, remote_header, remote_spec, state_machine_call,
+	state_machine_call_with_db_stats, state_machine_call_with_proof, webhook, SharedParams, State,
+	LOG_TARGET,
 };
 
 /// Configurations of the [`Command::OnRuntimeUpgrade`].
@@ -34,6 +68,320 @@ pub struct OnRuntimeUpgradeCmd {
 	/// The state type to use.
 	#[command(subcommand)]
 	pub state: State,
+
+	/// The maximum size, in MB, that the storage overlay of the migration may grow to.
+	///
+	/// The size of the overlay is always reported. If this is set and the overlay exceeds it, the
+	/// command fails. Overlays this large usually indicate a migration that will not scale.
+	///
+	/// The overlay is measured once the upgrade call returns, so this does not stop a migration
+	/// while it runs. Use `--max-memory-mb` to bound the memory of one that may never return.
+	#[arg(long)]
+	pub max_overlay_mb: Option<usize>,
+
+	/// A runtime api to call on the state before the upgrade.
+	///
+	/// Expected format is `Api_method[:ARGS]`, e.g. `MyApi_check_invariant` or
+	/// `MyApi_check_account:0x0101`, where `ARGS` are the hex encoded SCALE arguments of the
+	/// call. Without `ARGS`, the call gets no arguments. Can be provided multiple times. The
+	/// SCALE encoded result of each call is logged as hex.
+	#[arg(long, value_name = "Api_method[:ARGS]", value_parser = parse::runtime_call)]
+	pub pre_call: Vec<(String, Vec<u8>)>,
+
+	/// A runtime api to call on the state after the upgrade.
+	///
+	/// Same as `--pre-call`, but executed on top of the state changes of the upgrade.
+	#[arg(long, value_name = "Api_method[:ARGS]", value_parser = parse::runtime_call)]
+	pub post_call: Vec<(String, Vec<u8>)>,
+
+	/// What to do if the runtime of the remote node changes while the state is fetched.
+	///
+	/// Only has an effect with [`State::Live`]. The runtime version is read at the block whose
+	/// state is fetched, or at the best block if none is given, before and after the fetch.
+	#[arg(long, value_enum, default_value_t = OnVersionChange::Warn)]
+	pub on_version_change: OnVersionChange,
+
+	/// How many times to fetch the state again with `--on-version-change refetch`, before giving
+	/// up with an error.
+	#[arg(long, default_value_t = 3)]
+	pub max_refetches: u32,
+
+	/// Write a raw chain spec, whose genesis storage is the state after the upgrade, to this path.
+	///
+	/// All other fields are taken from the local chain spec (`--chain`).
+	#[arg(long, value_name = "PATH")]
+	pub export_chain_spec: Option<PathBuf>,
+
+	/// Execute the upgrade a second time, on top of the state changes of the first one.
+	///
+	/// A correct migration is a no-op the second time. The command fails if the second execution
+	/// changes any storage, or consumes more than `--run-twice-max-weight`.
+	#[arg(long)]
+	pub run_twice: bool,
+
+	/// The maximum ref time, in picoseconds, that the second execution of `--run-twice` may
+	/// consume.
+	///
+	/// The default leaves room for a migration to read its storage version.
+	#[arg(long, value_name = "PS", default_value_t = 1_000_000_000, requires = "run_twice")]
+	pub run_twice_max_weight: u64,
+
+	/// A hex encoded storage key prefix that the second execution of `--run-twice` is allowed to
+	/// change. Can be provided multiple times.
+	#[arg(long, value_name = "PREFIX", value_parser = parse::hex_bytes, requires = "run_twice")]
+	pub run_twice_allowed_prefix: Vec<Vec<u8>>,
+
+	/// The maximum number of seconds that the execution of the upgrade may take.
+	///
+	/// If exceeded, the command fails with a timeout error, and logs the time spent in each phase
+	/// before it. The execution itself cannot be interrupted, so it is abandoned on its own
+	/// thread, which does not keep the process from exiting. With several snapshots, it keeps a
+	/// CPU busy while the upgrade is executed against the remaining ones.
+	#[arg(long, value_name = "SECS")]
+	pub time_budget: Option<u64>,
+
+	/// Write the raw SCALE encoded result of the upgrade call, as hex, to this path, or to the
+	/// standard output if set to `stdout`.
+	///
+	/// The result is written before it is decoded, so it is available even if decoding fails.
+	#[arg(long, value_name = "PATH|stdout")]
+	pub dump_raw_result: Option<String>,
+
+	/// Fail if the upgrade removes any storage key that existed before it.
+	#[arg(long)]
+	pub forbid_key_removal: bool,
+
+	/// A hex encoded storage key prefix under which `--forbid-key-removal` allows removals, for
+	/// intentional cleanups. Can be provided multiple times.
+	#[arg(
+		long,
+		value_name = "PREFIX",
+		value_parser = parse::hex_bytes,
+		requires = "forbid_key_removal"
+	)]
+	pub allow_removal_prefix: Vec<Vec<u8>>,
+
+	/// Report the number of storage reads and writes of the upgrade.
+	///
+	/// Reads are those that reach the database rather than the storage overlay, writes are the
+	/// distinct keys changed. Use this to cross-check the `T::DbWeight` accounting of migrations.
+	#[arg(long)]
+	pub db_op_stats: bool,
+
+	/// Execute the upgrade without recording a storage proof.
+	///
+	/// This is faster, but the proof size of the upgrade is not reported.
+	#[arg(long)]
+	pub no_proof: bool,
+
+	/// Fail if fewer than `COUNT` storage keys start with the hex encoded `PREFIX` after the
+	/// upgrade. Can be provided multiple times.
+	///
+	/// A smoke test against migrations that accidentally wipe data.
+	#[arg(long, value_name = "PREFIX=COUNT", value_parser = parse::prefix_count)]
+	pub min_keys_under: Vec<(Vec<u8>, usize)>,
+
+	/// Write the events deposited by the upgrade to this path, or to the standard output if set
+	/// to `stdout`.
+	///
+	/// The output is a JSON object with the number of new events in `System::Events`, and an
+	/// array with the phase, pallet and event name of each of them, and its SCALE encoding as
+	/// hex. The names are taken from the metadata of the upgraded runtime, while the fields of
+	/// the events are left encoded.
+	///
+	/// If the runtime has no V14 metadata, or the events do not match it, `events` is `null`
+	/// and only the SCALE encoding of all new events together is written, as `encoded`.
+	#[arg(long, value_name = "PATH|stdout")]
+	pub dump_events: Option<String>,
+
+	/// Set `Timestamp::Now` to this value, in milliseconds, before the upgrade.
+	#[arg(long, value_name = "MILLISECONDS")]
+	pub mock_timestamp: Option<u64>,
+
+	/// Set `Authorship::Author` to this hex encoded account id before the upgrade.
+	///
+	/// Only has an effect on runtimes that include the authorship pallet.
+	#[arg(long, value_name = "ACCOUNT", value_parser = parse::hex_bytes)]
+	pub mock_author: Option<Vec<u8>>,
+
+	/// Set `System::Number` to this block number before the upgrade.
+	///
+	/// Use this to test migrations that behave differently at later blocks. Which block the state
+	/// is taken from is still chosen by the state source.
+	#[arg(long, value_name = "NUMBER")]
+	pub at_block_number: Option<u32>,
+
+	/// Fail if the upgrade changes any storage.
+	///
+	/// Use this with a state that is already at the new storage versions, to check that the
+	/// migrations are guarded by storage version checks, which is what fresh deployments rely
+	/// on. The consumed weight should then be no more than the reads of those checks, and the
+	/// command also fails if it is more than `--max-noop-weight`.
+	#[arg(long)]
+	pub assert_noop_on_migrated: bool,
+
+	/// The maximum ref time, in picoseconds, that the upgrade may consume with
+	/// `--assert-noop-on-migrated`.
+	///
+	/// The default leaves room for the migrations to read their storage versions.
+	#[arg(
+		long,
+		value_name = "PS",
+		default_value_t = 1_000_000_000,
+		requires = "assert_noop_on_migrated"
+	)]
+	pub max_noop_weight: u64,
+
+	/// Before the upgrade, check that the fetched state reproduces the state root that the node
+	/// reports for its block.
+	///
+	/// This catches a state that was fetched incompletely. Only supported for the live state of
+	/// all pallets, and `--state-version` must match the one of the chain.
+	#[arg(long)]
+	pub verify_against_node_root: bool,
+
+	/// Abort the upgrade once it has read more than this many storage items.
+	///
+	/// Catches migrations that loop over unbounded storage before they exhaust time or memory.
+	/// Only reads that reach the state count, not those served by the changes of the upgrade.
+	#[arg(long, value_name = "N")]
+	pub max_storage_reads: Option<u64>,
+
+	/// A runtime api returning the weight that the migrations declare, to compare against the
+	/// consumed weight.
+	///
+	/// Called with no arguments on the state before the upgrade, and must return a SCALE encoded
+	/// `Weight`. A consumed weight above the declared one is reported as a warning, as it means
+	/// that blocks with the upgrade may be overweight. Both weights, and their difference, are
+	/// reported per dimension.
+	#[arg(long, value_name = "Api_method")]
+	pub declared_weight_call: Option<String>,
+
+	/// Provide the offchain worker and offchain database extensions to the upgrade.
+	///
+	/// Both are backed by an empty in-memory offchain database.
+	#[arg(long)]
+	pub with_offchain: bool,
+
+	/// Provide the offchain worker extension to the upgrade with its clock frozen at this UNIX
+	/// time, in milliseconds.
+	///
+	/// `sp_io::offchain::timestamp` then returns this value throughout the upgrade, so that
+	/// weights which depend on the time elapsed since a stored timestamp are reproducible. The
+	/// clock only moves if the upgrade sleeps. Implies `--with-offchain`.
+	#[arg(long, value_name = "MILLISECONDS")]
+	pub freeze_time: Option<u64>,
+
+	/// Provide the keystore extension to the upgrade, backed by an empty in-memory keystore.
+	#[arg(long)]
+	pub with_keystore: bool,
+
+	/// Print a table of the pallets whose storage version the upgrade changed, with the old and
+	/// new version.
+	///
+	/// Pallets are named if they are among the `--pallet`s of the live state, and are otherwise
+	/// shown as the `twox_128` hash of their name.
+	#[arg(long)]
+	pub version_changes: bool,
+
+	/// Report the number of keys and the total size of the state, including child tries, before
+	/// and after the upgrade.
+	///
+	/// This iterates the entire state, which takes a while for large chains.
+	#[arg(long)]
+	pub state_size: bool,
+
+	/// POST the results to this `http://` or `https://` url once the command completes, retrying
+	/// on failure.
+	///
+	/// The body is a JSON object with a `states` array, holding one object per state with the
+	/// fields `state`, `ok`, `weight`, `total_weight` and `overlay_size`, or `error` if the
+	/// upgrade failed against it, and the number of `failed` states. Weights are objects with the
+	/// fields `ref_time` and `proof_size`. Nothing is redacted.
+	#[arg(long, value_name = "URL", value_parser = parse::http_url)]
+	pub webhook: Option<String>,
+
+	/// Wasm runtimes to upgrade to in sequence, instead of the runtime of `--chain`.
+	///
+	/// The upgrade to each runtime is executed on top of the state left by the previous one, and
+	/// its weight is reported. All checks of this command apply to the upgrade to the last
+	/// runtime, and the cumulative weight of all of them is reported.
+	#[arg(long, value_name = "PATH", num_args = 1..)]
+	pub runtime: Vec<PathBuf>,
+
+	/// Report the peak number of bytes allocated on the wasm heap by the upgrade.
+	///
+	/// The native runtime does not track its allocations, so the upgrade is executed once more,
+	/// in wasm and on top of the same state, to measure it. The changes of that execution are
+	/// discarded.
+	#[arg(long)]
+	pub heap_peak: bool,
+
+	/// Upgrade to the runtime that the remote node has on chain, instead of the runtime of
+	/// `--chain`.
+	///
+	/// The `:code` is fetched along with the live state, from the same block. Use this to measure
+	/// the migrations of a runtime that is already enacted. Only supported for the live state.
+	#[arg(long, conflicts_with = "runtime")]
+	pub use_remote_runtime: bool,
+
+	/// Write the time spent in each phase of the command to this path, as folded stacks.
+	///
+	/// The phases are fetching the state, the runtime api calls before the upgrade, the upgrade
+	/// itself and the checks after it. Each line is a stack of `;` separated frames and the
+	/// microseconds spent in it, ready to be rendered by `inferno-flamegraph` or
+	/// `flamegraph.pl`. The time within the runtime is not broken down further.
+	#[arg(long, value_name = "PATH")]
+	pub folded_stacks: Option<PathBuf>,
+
+	/// Write the hashes of the trie nodes fetched by the upgrade to this path, in the order they
+	/// were fetched, one hex encoded hash per line.
+	///
+	/// Nodes fetched more than once are listed each time. Use this to analyze the access pattern
+	/// of a migration, e.g. whether it reads the state randomly or sequentially.
+	#[arg(long, value_name = "PATH", conflicts_with = "no_proof")]
+	pub node_access_log: Option<PathBuf>,
+
+	/// A runtime api validating the state after the upgrade.
+	///
+	/// Called with no arguments on top of the state changes of the upgrade, and must return a
+	/// SCALE encoded `Result<(), Vec<u8>>`. The command fails if it returns an error, showing the
+	/// error bytes as text if they are UTF-8, and as hex otherwise.
+	#[arg(long, value_name = "Api_method")]
+	pub validate_api: Option<String>,
+
+	/// Print a single summary line to the standard error once the command completes, for CI to
+	/// parse.
+	///
+	/// The line is `TRY_RUNTIME_RESULT status=<ok|fail> ref_time=<N> proof_size=<N>
+	/// checks=<pass|fail>`, with the fields always in this order. `status` is `ok` and `checks` is
+	/// `pass` if the upgrade and all the requested checks succeeded against every state, and they
+	/// are `fail` otherwise. Each weight dimension is the maximum consumed by the upgrade against
+	/// any state, or zero if it failed against all of them.
+	#[arg(long)]
+	pub ci_summary: bool,
+}
+
+/// What to do when the runtime of the remote node changes while its state is fetched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OnVersionChange {
+	/// Log a warning and use the fetched state.
+	Warn,
+	/// Fail the command.
+	Fail,
+	/// Fetch the state again, until the runtime does not change during the fetch.
+	Refetch,
+}
+
+/// The outcome of executing the upgrade against one state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct UpgradeSummary {
+	/// The weight consumed by the upgrade.
+	pub weight: Weight,
+	/// The total allowed block weight of the runtime.
+	pub total_weight: Weight,
+	/// The size of the storage overlay of the upgrade, in bytes.
+	pub overlay_size: usize,
 }
 
 pub(crate) async fn on_runtime_upgrade<Block, ExecDispatch>(
@@ -50,16 +398,162 @@ where
 	<NumberFor<Block> as FromStr>::Err: Debug,
 	ExecDispatch: NativeExecutionDispatch + 'static,
 {
-	let executor = build_executor(&shared, &config);
-	let execution = shared.execution;
+	let states = command.state.per_snapshot();
+	let mut stacks = FoldedStacks::default();
+	if states.len() == 1 {
+		let result = upgrade_state::<Block, ExecDispatch>(
+			&shared,
+			&command,
+			&config,
+			&states[0],
+			&mut stacks,
+			&["on_runtime_upgrade"],
+		)
+		.await;
+		write_folded_stacks(&command, &stacks)?;
+		let results = [(&states[0], result)];
+		if command.ci_summary {
+			eprintln!("{}", ci_summary(&results));
+		}
+		notify_webhook(&command, &results).await;
+		let [(_, result)] = results;
+		return result.map(|_| ())
+	}
 
-	let ext = {
-		let builder = command.state.builder::<Block>()?.state_version(shared.state_version);
-		let (code_key, code) = extract_code(&config.chain_spec)?;
-		builder.inject_hashed_key_value(&[(code_key, code)]).build().await?
-	};
+	let stacks = std::sync::Mutex::new(stacks);
+	let results = upgrade_each(&states, |index, state| {
+		let (shared, command, config, stacks) = (&shared, &command, &config, &stacks);
+		async move {
+			let frame = format!("state_{}", index);
+			let mut state_stacks = FoldedStacks::default();
+			let result = upgrade_state::<Block, ExecDispatch>(
+				shared,
+				command,
+				config,
+				state,
+				&mut state_stacks,
+				&["on_runtime_upgrade", &frame],
+			)
+			.await;
+			stacks.lock().expect("the stacks are never poisoned; qed").append(state_stacks);
+			result
+		}
+	})
+	.await;
+
+	let stacks = stacks.into_inner().expect("the stacks are never poisoned; qed");
+	write_folded_stacks(&command, &stacks)?;
+	if command.ci_summary {
+		eprintln!("{}", ci_summary(&results));
+	}
+	notify_webhook(&command, &results).await;
+	ensure_all_upgraded(&results)
+}
+
+/// Execute `upgrade` against each of `states` in order, and return the result against each of
+/// them.
+async fn upgrade_each<'a, Upgrade, Fut>(
+	states: &'a [State],
+	mut upgrade: Upgrade,
+) -> Vec<(&'a State, sc_cli::Result<UpgradeSummary>)>
+where
+	Upgrade: FnMut(usize, &'a State) -> Fut,
+	Fut: std::future::Future<Output = sc_cli::Result<UpgradeSummary>>,
+{
+	let mut results = Vec::with_capacity(states.len());
+	for (index, state) in states.iter().enumerate() {
+		log::info!(target: LOG_TARGET, "executing the upgrade against {:?}", state);
+		let result = upgrade(index, state).await;
+		if let Err(ref e) = result {
+			log::error!(target: LOG_TARGET, "upgrade failed against {:?}: {}", state, e);
+		}
+		results.push((state, result));
+	}
+	results
+}
+
+/// Log the aggregate of the `results` of the upgrade against several states, and fail, naming
+/// the failed states, if it failed against any of them.
+fn ensure_all_upgraded(results: &[(&State, sc_cli::Result<UpgradeSummary>)]) -> sc_cli::Result<()> {
+	let mut max_weight = Weight::zero();
+	let mut failed = Vec::new();
+	for (state, result) in results {
+		match result {
+			Ok(summary) => {
+				max_weight = max_weight.max(summary.weight);
+				log::info!(
+					target: LOG_TARGET,
+					"{:?}: ok, weight ({} ps, {} byte), overlay {} bytes",
+					state,
+					summary.weight.ref_time(),
+					summary.weight.proof_size(),
+					summary.overlay_size,
+				);
+			},
+			Err(e) => {
+				failed.push(format!("{:?}: {}", state, e));
+				log::info!(target: LOG_TARGET, "{:?}: failed, {}", state, e);
+			},
+		}
+	}
+	log::info!(
+		target: LOG_TARGET,
+		"executed the upgrade against {} states, {} failed, maximum weight ({} ps, {} byte)",
+		results.len(),
+		failed.len(),
+		max_weight.ref_time(),
+		max_weight.proof_size(),
+	);
+
+	if !failed.is_empty() {
+		return Err(format!(
+			"upgrade failed against {} of {} states; {}",
+			failed.len(),
+			results.len(),
+			failed.join("; "),
+		)
+		.into())
+	}
+	Ok(())
+}
 
-	if let Some(uri) = command.state.live_uri() {
+/// Write `stacks` to the [`OnRuntimeUpgradeCmd::folded_stacks`] path, if set.
+fn write_folded_stacks(command: &OnRuntimeUpgradeCmd, stacks: &FoldedStacks) -> sc_cli::Result<()> {
+	if let Some(path) = &command.folded_stacks {
+		stacks.write(path)?;
+		log::info!(target: LOG_TARGET, "wrote the time spent per phase to {:?}", path);
+	}
+	Ok(())
+}
+
+/// Execute the upgrade, and all the checks requested by `command`, against `state`.
+///
+/// The time spent in each phase is recorded in `stacks`, below the frames of `stack`.
+async fn upgrade_state<Block, ExecDispatch>(
+	shared: &SharedParams,
+	command: &OnRuntimeUpgradeCmd,
+	config: &Configuration,
+	state: &State,
+	stacks: &mut FoldedStacks,
+	stack: &[&str],
+) -> sc_cli::Result<UpgradeSummary>
+where
+	Block: BlockT + serde::de::DeserializeOwned,
+	Block::Hash: FromStr,
+	<Block::Hash as FromStr>::Err: Debug,
+	Block::Header: serde::de::DeserializeOwned,
+	NumberFor<Block>: FromStr,
+	<NumberFor<Block> as FromStr>::Err: Debug,
+	ExecDispatch: NativeExecutionDispatch + 'static,
+{
+	let started = Instant::now();
+	let executor = build_executor(shared, config);
+	let execution = shared.execution;
+	let (mut ext, code) = fetch_state::<Block>(shared, command, config, state).await?;
+	stacks.record(stack, "fetch_state", started);
+
+	if let Some(uri) = state.live_uri() {
+		let started = Instant::now();
 		let (expected_spec_name, expected_spec_version, _) =
 			local_spec::<Block, ExecDispatch>(&ext, &executor);
 		ensure_matching_spec::<Block>(
@@ -69,27 +563,2344 @@ where
 			shared.no_spec_check_panic,
 		)
 		.await;
+		stacks.record(stack, "check_spec", started);
 	}
 
-	let (_, encoded_result) = state_machine_call_with_proof::<Block, ExecDispatch>(
-		&ext,
+	let intermediate_runtimes = match command.runtime.split_last() {
+		Some((_, intermediate_runtimes)) => intermediate_runtimes,
+		None => &[],
+	};
+	let started = Instant::now();
+	let step_weights =
+		chain_upgrades(&mut ext, intermediate_runtimes, &code.1, shared.state_version, |ext| {
+			execute_upgrade::<Block, ExecDispatch>(
+				ext,
+				&executor,
+				execution,
+				upgrade_extensions(command),
+				true,
+				command.max_storage_reads,
+				None,
+				None,
+			)
+			.map(|(changes, weight, _, _)| (changes, weight))
+		})?;
+	if !intermediate_runtimes.is_empty() {
+		stacks.record(stack, "chained_upgrades", started);
+	}
+
+	let started = Instant::now();
+	let declared_weight =
+		pre_upgrade_calls::<Block, ExecDispatch>(command, &ext, &executor, execution)?;
+	stacks.record(stack, "pre_calls", started);
+
+	let started = Instant::now();
+	let upgrade =
+		execute_upgrade_within_budget::<Block, ExecDispatch>(command, ext, &executor, execution)
+			.await;
+	let (mut ext, (mut changes, weight, total_weight, db_stats)) = upgrade.map_err(|e| {
+		stacks.record(stack, "execute_upgrade", started);
+		log::error!(
+			target: LOG_TARGET,
+			"microseconds spent per phase before the upgrade failed:\n{}",
+			stacks.folded(),
+		);
+		e
+	})?;
+	stacks.record(stack, "execute_upgrade", started);
+	report_weight(command, weight, total_weight, &step_weights, declared_weight, db_stats);
+
+	if command.heap_peak {
+		let started = Instant::now();
+		report_heap_peak::<ExecDispatch>(command, shared, config, &ext)?;
+		stacks.record(stack, "heap_peak", started);
+	}
+
+	let started = Instant::now();
+	let overlay_size = check_overlay_size(&changes, command.max_overlay_mb)?;
+	let event_decoder = match command.dump_events {
+		Some(_) => event_decoder::<Block, ExecDispatch>(&ext, &executor, execution),
+		None => None,
+	};
+	check_changes(command, state, &ext, &changes, weight, db_stats, event_decoder.as_ref())?;
+	check_upgraded_state::<Block, ExecDispatch>(
+		shared,
+		command,
+		config,
+		&mut ext,
+		&mut changes,
 		&executor,
 		execution,
-		"TryRuntime_on_runtime_upgrade",
-		&[],
-		Default::default(), // we don't really need any extensions here.
 	)?;
+	stacks.record(stack, "checks", started);
+	Ok(UpgradeSummary { weight, total_weight, overlay_size })
+}
 
-	let (weight, total_weight) = <(Weight, Weight) as Decode>::decode(&mut &*encoded_result)
-		.map_err(|e| format!("failed to decode weight: {:?}", e))?;
+/// Fetch `state`, with the runtime that `command` upgrades to and the mocked block context it
+/// asks for, and return it along with the `:code` of that runtime.
+async fn fetch_state<Block>(
+	shared: &SharedParams,
+	command: &OnRuntimeUpgradeCmd,
+	config: &Configuration,
+	state: &State,
+) -> sc_cli::Result<(TestExternalities, (StorageKey, StorageData))>
+where
+	Block: BlockT + serde::de::DeserializeOwned,
+	Block::Hash: FromStr,
+	<Block::Hash as FromStr>::Err: Debug,
+	Block::Header: serde::de::DeserializeOwned,
+{
+	let local_code = match (command.use_remote_runtime, command.runtime.last()) {
+		(true, _) if state.live_uri().is_none() =>
+			return Err("--use-remote-runtime needs the live state".into()),
+		(true, _) => None,
+		(false, Some(path)) => Some(runtime_code(path)?),
+		(false, None) => Some(extract_code(&config.chain_spec)?),
+	};
+	let mut injected = local_code.iter().cloned().collect::<Vec<_>>();
+	injected.extend(mocked_block_context::<Block>(command));
+	let all_pallets = matches!(state, State::Live { pallet, .. } if pallet.is_empty());
+	if command.verify_against_node_root && !all_pallets {
+		return Err("--verify-against-node-root needs the live state of all pallets".into())
+	}
+
+	let at = match state {
+		State::Live { at: Some(at), .. } => Some(hash_of::<Block>(at)?),
+		_ => None,
+	};
+	let spec = move || async move {
+		match state.live_uri() {
+			Some(uri) => remote_spec::<Block>(&uri, at).await.map(Some),
+			None => Ok(None),
+		}
+	};
+	let fetch = move || async move {
+		let mut builder = state.builder::<Block>()?.state_version(shared.state_version);
+		let mut expected_root = None;
+		if let (true, State::Live { uri, .. }) = (command.verify_against_node_root, state) {
+			let header = remote_header::<Block>(uri, at).await?;
+			// pin the block, so that the state and the root are of the same block.
+			builder = builder.overwrite_online_at(header.hash());
+			expected_root = Some(*header.state_root());
+		}
+		if command.use_remote_runtime {
+			builder = builder.inject_hashed_key(well_known_keys::CODE);
+		}
+		Ok::<_, sc_cli::Error>((builder.build().await?, expected_root))
+	};
+	let (mut ext, expected_root) =
+		fetch_unchanged(command.on_version_change, command.max_refetches, spec, fetch).await?;
+
+	if let Some(expected_root) = expected_root {
+		ensure_state_root(&ext, expected_root.as_ref())?;
+	}
+	// only now, so that the injected keys do not change the root of the fetched state.
+	inject_keys(&mut ext, injected)?;
+	let code = target_code(&ext, local_code)?;
+	Ok((ext, code))
+}
+
+/// Run `fetch`, reading the runtime spec with `spec` before and after it, and act on a change
+/// of the spec in between according to `on_change`.
+///
+/// `spec` returns `None` if there is no remote node to read the spec of. With
+/// [`OnVersionChange::Refetch`], `fetch` is run again at most `max_refetches` times.
+async fn fetch_unchanged<T, Spec, SpecFut, Fetch, FetchFut>(
+	on_change: OnVersionChange,
+	max_refetches: u32,
+	mut spec: Spec,
+	mut fetch: Fetch,
+) -> sc_cli::Result<T>
+where
+	Spec: FnMut() -> SpecFut,
+	SpecFut: std::future::Future<Output = sc_cli::Result<Option<(String, u32)>>>,
+	Fetch: FnMut() -> FetchFut,
+	FetchFut: std::future::Future<Output = sc_cli::Result<T>>,
+{
+	let mut refetches = 0;
+	loop {
+		let spec_before = spec().await?;
+		let fetched = fetch().await?;
+		let spec_before = match spec_before {
+			Some(spec_before) => spec_before,
+			None => return Ok(fetched),
+		};
+		let spec_after = spec().await?.ok_or("the remote node went away while fetching")?;
+		if spec_before == spec_after {
+			return Ok(fetched)
+		}
+
+		let msg = format!(
+			"remote runtime changed from {:?} to {:?} while fetching the state",
+			spec_before, spec_after,
+		);
+		match on_change {
+			OnVersionChange::Warn => {
+				log::warn!(target: LOG_TARGET, "{}; use --on-version-change to act on it", msg);
+				return Ok(fetched)
+			},
+			OnVersionChange::Fail => return Err(msg.into()),
+			OnVersionChange::Refetch if refetches >= max_refetches =>
+				return Err(format!(
+					"{}, giving up after {} refetches; see --max-refetches",
+					msg, max_refetches,
+				)
+				.into()),
+			OnVersionChange::Refetch => {
+				refetches += 1;
+				log::warn!(target: LOG_TARGET, "{}, fetching again", msg);
+			},
+		}
+	}
+}
+
+/// Execute the [`OnRuntimeUpgradeCmd::pre_call`]s on top of `ext`, and return the weight that
+/// the [`OnRuntimeUpgradeCmd::declared_weight_call`] declares, if set.
+fn pre_upgrade_calls<Block: BlockT, ExecDispatch: NativeExecutionDispatch + 'static>(
+	command: &OnRuntimeUpgradeCmd,
+	ext: &TestExternalities,
+	executor: &NativeElseWasmExecutor<ExecDispatch>,
+	execution: sc_cli::ExecutionStrategy,
+) -> sc_cli::Result<Option<Weight>> {
+	runtime_api_calls::<Block, ExecDispatch>(ext, executor, execution, &command.pre_call)?;
+
+	let method = match &command.declared_weight_call {
+		Some(method) => method,
+		None => return Ok(None),
+	};
+	let encoded = runtime_api_calls::<Block, ExecDispatch>(
+		ext,
+		executor,
+		execution,
+		&[(method.clone(), Vec::new())],
+	)?;
+	let weight = Weight::decode(&mut &*encoded[0])
+		.map_err(|e| format!("failed to decode the weight returned by {}: {:?}", method, e))?;
+	Ok(Some(weight))
+}
+
+/// Execute the upgrade on top of `ext` as `command` asks, see [`execute_upgrade`], within the
+/// [`OnRuntimeUpgradeCmd::time_budget`] if set, and return `ext` along with the results.
+async fn execute_upgrade_within_budget<Block, ExecDispatch>(
+	command: &OnRuntimeUpgradeCmd,
+	ext: TestExternalities,
+	executor: &NativeElseWasmExecutor<ExecDispatch>,
+	execution: sc_cli::ExecutionStrategy,
+) -> sc_cli::Result<(TestExternalities, (OverlayedChanges, Weight, Weight, DbOpStats))>
+where
+	Block: BlockT,
+	ExecDispatch: NativeExecutionDispatch + 'static,
+{
+	let time_budget = match command.time_budget {
+		Some(time_budget) => Duration::from_secs(time_budget),
+		None => {
+			let upgrade = execute_upgrade::<Block, ExecDispatch>(
+				&ext,
+				executor,
+				execution,
+				upgrade_extensions(command),
+				command.no_proof,
+				command.max_storage_reads,
+				command.dump_raw_result.as_deref(),
+				command.node_access_log.as_deref(),
+			)?;
+			return Ok((ext, upgrade))
+		},
+	};
+
+	let executor = executor.clone();
+	let extensions = upgrade_extensions(command);
+	let no_proof = command.no_proof;
+	let max_storage_reads = command.max_storage_reads;
+	let dump_raw_result = command.dump_raw_result.clone();
+	let node_access_log = command.node_access_log.clone();
+	let (ext, upgrade) = within_time_budget(time_budget, move || {
+		let upgrade = execute_upgrade::<Block, ExecDispatch>(
+			&ext,
+			&executor,
+			execution,
+			extensions,
+			no_proof,
+			max_storage_reads,
+			dump_raw_result.as_deref(),
+			node_access_log.as_deref(),
+		);
+		(ext, upgrade)
+	})
+	.await?;
+	Ok((ext, upgrade?))
+}
+
+/// Log the `weight` consumed by the upgrade, and what `command` asks to report about it.
+///
+/// `step_weights` are the weights of the chained upgrades before it, and `declared_weight` is
+/// the weight that the migrations declare, if known.
+fn report_weight(
+	command: &OnRuntimeUpgradeCmd,
+	weight: Weight,
+	total_weight: Weight,
+	step_weights: &[Weight],
+	declared_weight: Option<Weight>,
+	db_stats: DbOpStats,
+) {
 	log::info!(
 		target: LOG_TARGET,
-		"TryRuntime_on_runtime_upgrade executed without errors. Consumed weight = ({} ps, {} byte), total weight = ({} ps, {} byte) ({:.2} %, {:.2} %).",
-		weight.ref_time(), weight.proof_size(),
-		total_weight.ref_time(), total_weight.proof_size(),
-		(weight.ref_time() as f64 / total_weight.ref_time().max(1) as f64) * 100.0,
+		"TryRuntime_on_runtime_upgrade executed without errors. {}",
+		weight_summary(weight, total_weight, command.no_proof),
+	);
+	if !step_weights.is_empty() {
+		let cumulative = step_weights.iter().fold(weight, |sum, step| sum.saturating_add(*step));
+		log::info!(
+			target: LOG_TARGET,
+			"the {} chained upgrades consumed weight ({} ps, {} byte) in total",
+			step_weights.len() + 1,
+			cumulative.ref_time(),
+			cumulative.proof_size(),
+		);
+	}
+	if let Some(declared_weight) = declared_weight {
+		match under_declaration(weight, declared_weight) {
+			Some(warning) => log::warn!(target: LOG_TARGET, "{}", warning),
+			None => log::info!(
+				target: LOG_TARGET,
+				"consumed weight is within the declared weight ({} ps, {} byte)",
+				declared_weight.ref_time(),
+				declared_weight.proof_size(),
+			),
+		}
+		log::info!(
+			target: LOG_TARGET,
+			"declared and consumed weight of the migrations:\n{}",
+			weight_delta_table(weight, declared_weight),
+		);
+	}
+	if command.db_op_stats {
+		log::info!(
+			target: LOG_TARGET,
+			"storage operations of the migration: {} reads ({} distinct keys), {} writes",
+			db_stats.reads,
+			db_stats.unique_reads,
+			db_stats.writes,
+		);
+	}
+}
+
+/// Describe the `weight` consumed by the upgrade, out of the `total_weight` of a block.
+///
+/// The proof size is left out if `no_proof` is set, since the storage proof of the upgrade is not
+/// recorded then.
+fn weight_summary(weight: Weight, total_weight: Weight, no_proof: bool) -> String {
+	let ref_time_percent =
+		(weight.ref_time() as f64 / total_weight.ref_time().max(1) as f64) * 100.0;
+	if no_proof {
+		return format!(
+			"Consumed weight = {} ps, total weight = {} ps ({:.2} %), proof size not recorded.",
+			weight.ref_time(),
+			total_weight.ref_time(),
+			ref_time_percent,
+		)
+	}
+	format!(
+		"Consumed weight = ({} ps, {} byte), total weight = ({} ps, {} byte) ({:.2} %, {:.2} %).",
+		weight.ref_time(),
+		weight.proof_size(),
+		total_weight.ref_time(),
+		total_weight.proof_size(),
+		ref_time_percent,
 		(weight.proof_size() as f64 / total_weight.proof_size().max(1) as f64) * 100.0,
+	)
+}
+
+/// Log the peak wasm heap usage of the upgrade on top of `ext`, see
+/// [`OnRuntimeUpgradeCmd::heap_peak`].
+fn report_heap_peak<ExecDispatch: NativeExecutionDispatch + 'static>(
+	command: &OnRuntimeUpgradeCmd,
+	shared: &SharedParams,
+	config: &Configuration,
+	ext: &TestExternalities,
+) -> sc_cli::Result<()> {
+	let heap_peak = measure_heap_peak(|| {
+		wasm_upgrade_with_allocation_stats::<ExecDispatch>(
+			ext,
+			shared,
+			config,
+			upgrade_extensions(command),
+		)
+	})?;
+	log::info!(
+		target: LOG_TARGET,
+		"peak wasm heap usage of the migration is {:.2} MB ({} bytes)",
+		heap_peak as f64 / MEGABYTE as f64,
+		heap_peak,
 	);
+	Ok(())
+}
+
+/// Log the size of the storage overlay held in `changes`, and fail if it exceeds
+/// `max_overlay_mb`, if set. Returns the size in bytes.
+///
+/// This runs after the upgrade call, on the changes it returned.
+fn check_overlay_size(
+	changes: &OverlayedChanges,
+	max_overlay_mb: Option<usize>,
+) -> sc_cli::Result<usize> {
+	let overlay_size = overlay_size(changes);
+	log::info!(
+		target: LOG_TARGET,
+		"storage overlay of the migration is {:.2} MB ({} bytes)",
+		overlay_size as f64 / MEGABYTE as f64,
+		overlay_size,
+	);
+	if let Some(max_overlay_mb) = max_overlay_mb {
+		if overlay_size > max_overlay_mb.saturating_mul(MEGABYTE) {
+			return Err(format!(
+				"storage overlay of the migration ({} bytes) exceeds --max-overlay-mb {}",
+				overlay_size, max_overlay_mb,
+			)
+			.into())
+		}
+	}
+	Ok(overlay_size)
+}
 
+/// Run the checks and reports that `command` asks for on the `changes` of the upgrade, before
+/// they are applied to `ext`.
+///
+/// `weight` and `db_stats` are those of the upgrade, and `event_decoder` splits the events that
+/// it deposits.
+fn check_changes(
+	command: &OnRuntimeUpgradeCmd,
+	state: &State,
+	ext: &TestExternalities,
+	changes: &OverlayedChanges,
+	weight: Weight,
+	db_stats: DbOpStats,
+	event_decoder: Option<&EventDecoder>,
+) -> sc_cli::Result<()> {
+	if command.state_size {
+		report_state_size(ext, changes)?;
+	}
+	if command.version_changes {
+		let pallet_names = match state {
+			State::Live { pallet, .. } => &pallet[..],
+			_ => &[],
+		};
+		let version_changes = version_changes(ext, changes)?;
+		log::info!(
+			target: LOG_TARGET,
+			"storage versions changed by the migration:\n{}",
+			version_changes_table(&version_changes, pallet_names),
+		);
+	}
+	if command.assert_noop_on_migrated {
+		let what = "the upgrade of already migrated state";
+		ensure_unchanged(ext, changes, &[], what)?;
+		ensure_trivial_weight(what, weight, command.max_noop_weight, "--max-noop-weight")?;
+		log::info!(
+			target: LOG_TARGET,
+			"the upgrade of already migrated state changed no storage, and consumed weight ({} ps, {} byte) with {} reads",
+			weight.ref_time(),
+			weight.proof_size(),
+			db_stats.reads,
+		);
+	}
+	if let Some(ref target) = command.dump_events {
+		dump_events(target, ext, changes, event_decoder)?;
+	}
+	if command.forbid_key_removal {
+		ensure_no_removals(ext, changes, &command.allow_removal_prefix)?;
+	}
+	Ok(())
+}
+
+/// Apply the `changes` of the upgrade to `ext`, if any of the checks that `command` asks for
+/// need the state after the upgrade, and run them.
+fn check_upgraded_state<Block: BlockT, ExecDispatch: NativeExecutionDispatch + 'static>(
+	shared: &SharedParams,
+	command: &OnRuntimeUpgradeCmd,
+	config: &Configuration,
+	ext: &mut TestExternalities,
+	changes: &mut OverlayedChanges,
+	executor: &NativeElseWasmExecutor<ExecDispatch>,
+	execution: sc_cli::ExecutionStrategy,
+) -> sc_cli::Result<()> {
+	if !command.post_call.is_empty() ||
+		command.validate_api.is_some() ||
+		command.export_chain_spec.is_some() ||
+		command.run_twice ||
+		!command.min_keys_under.is_empty()
+	{
+		apply_changes(ext, changes, shared.state_version)?;
+	}
+
+	ensure_min_keys(ext, &command.min_keys_under)?;
+
+	if command.run_twice {
+		ensure_idempotent::<Block, ExecDispatch>(command, ext, executor, execution)?;
+	}
+
+	runtime_api_calls::<Block, ExecDispatch>(ext, executor, execution, &command.post_call)?;
+
+	if let Some(method) = &command.validate_api {
+		let encoded = runtime_api_calls::<Block, ExecDispatch>(
+			ext,
+			executor,
+			execution,
+			&[(method.clone(), Vec::new())],
+		)?;
+		ensure_valid(method, &encoded[0])?;
+		log::info!(target: LOG_TARGET, "{} validated the state after the upgrade", method);
+	}
+
+	if let Some(ref path) = command.export_chain_spec {
+		export_chain_spec(config.chain_spec.cloned_box(), ext, path)?;
+		log::info!(target: LOG_TARGET, "exported post-upgrade chain spec to {:?}", path);
+	}
+	Ok(())
+}
+
+/// Log the size of the state of `ext` before and after applying `changes`, see
+/// [`OnRuntimeUpgradeCmd::state_size`].
+fn report_state_size(ext: &TestExternalities, changes: &OverlayedChanges) -> sc_cli::Result<()> {
+	let (before, after) = state_sizes(ext, changes)?;
+	log::info!(
+		target: LOG_TARGET,
+		"state size: {} keys, {} bytes before the migration, {} keys, {} bytes after it \
+		({:+} keys, {:+} bytes)",
+		before.keys,
+		before.bytes,
+		after.keys,
+		after.bytes,
+		after.keys as i64 - before.keys as i64,
+		after.bytes as i64 - before.bytes as i64,
+	);
 	Ok(())
 }
+
+/// A decoder of the events of the runtime of `ext`, from its metadata.
+///
+/// Returns `None`, with a warning, if the runtime has no usable metadata.
+fn event_decoder<Block: BlockT, ExecDispatch: NativeExecutionDispatch + 'static>(
+	ext: &TestExternalities,
+	executor: &NativeElseWasmExecutor<ExecDispatch>,
+	execution: sc_cli::ExecutionStrategy,
+) -> Option<EventDecoder> {
+	let decoder = state_machine_call::<Block, ExecDispatch>(
+		ext,
+		executor,
+		execution,
+		"Metadata_metadata",
+		&[],
+		Default::default(),
+	)
+	.map_err(|e| e.to_string())
+	.and_then(|(_, encoded)| {
+		<Vec<u8>>::decode(&mut &*encoded).map_err(|e| format!("invalid metadata: {}", e))
+	})
+	.and_then(|metadata| EventDecoder::new(&metadata));
+	match decoder {
+		Ok(decoder) => Some(decoder),
+		Err(e) => {
+			log::warn!(target: LOG_TARGET, "the events will not be split: {}", e);
+			None
+		},
+	}
+}
+
+/// Write the events that `changes` deposit on top of `ext` to `target`, split by `decoder` if
+/// set, see [`OnRuntimeUpgradeCmd::dump_events`].
+fn dump_events(
+	target: &str,
+	ext: &TestExternalities,
+	changes: &OverlayedChanges,
+	decoder: Option<&EventDecoder>,
+) -> sc_cli::Result<()> {
+	let key = [twox_128(b"System"), twox_128(b"Events")].concat();
+	let before = ext
+		.backend
+		.storage(&key)
+		.map_err(|e| format!("failed to read System::Events: {}", e))?;
+	let after = match changes.storage(&key) {
+		Some(after) => after.map(|v| v.to_vec()),
+		None => before.clone(),
+	};
+	let (count, events) = new_events(before.as_deref(), after.as_deref())?;
+	log::info!(target: LOG_TARGET, "the upgrade deposited {} events", count);
+	let records = decoder.map(|decoder| decoder.decode(count, &events)).transpose();
+	let records = records.unwrap_or_else(|e| {
+		log::warn!(target: LOG_TARGET, "the events do not match the runtime metadata: {}", e);
+		None
+	});
+	write_to(target, &events_json(count, &events, records.as_deref()).to_string())
+}
+
+/// The JSON of [`OnRuntimeUpgradeCmd::dump_events`], for `count` new `events`, split into
+/// `records` if known.
+fn events_json(count: u32, events: &[u8], records: Option<&[EventRecord]>) -> serde_json::Value {
+	let hex = |encoded: &[u8]| format!("0x{}", HexDisplay::from(&encoded));
+	match records {
+		Some(records) => {
+			let records = records
+				.iter()
+				.map(|record| {
+					serde_json::json!({
+						"phase": record.phase,
+						"pallet_index": record.pallet.0,
+						"pallet": record.pallet.1,
+						"event_index": record.variant.0,
+						"event": record.variant.1,
+						"encoded": hex(&record.encoded),
+					})
+				})
+				.collect::<Vec<_>>();
+			serde_json::json!({ "count": count, "events": records })
+		},
+		None => serde_json::json!({ "count": count, "events": null, "encoded": hex(events) }),
+	}
+}
+
+/// Fail if `changes` remove any key of `ext` that is not under one of `allowed_prefixes`.
+fn ensure_no_removals(
+	ext: &TestExternalities,
+	changes: &OverlayedChanges,
+	allowed_prefixes: &[Vec<u8>],
+) -> sc_cli::Result<()> {
+	let removed = removed_keys(ext, changes)?
+		.into_iter()
+		.filter(|key| !allowed_prefixes.iter().any(|prefix| key.starts_with(prefix)))
+		.map(|key| format!("0x{}", HexDisplay::from(&key)))
+		.collect::<Vec<_>>();
+	if !removed.is_empty() {
+		return Err(format!(
+			"the upgrade removed {} storage keys outside of --allow-removal-prefix: {:?}",
+			removed.len(),
+			removed,
+		)
+		.into())
+	}
+	Ok(())
+}
+
+/// Execute the upgrade a second time on top of `ext`, which holds the changes of the first one,
+/// and fail if it changes storage outside of the
+/// [`OnRuntimeUpgradeCmd::run_twice_allowed_prefix`]es, or consumes more than
+/// [`OnRuntimeUpgradeCmd::run_twice_max_weight`].
+fn ensure_idempotent<Block: BlockT, ExecDispatch: NativeExecutionDispatch + 'static>(
+	command: &OnRuntimeUpgradeCmd,
+	ext: &TestExternalities,
+	executor: &NativeElseWasmExecutor<ExecDispatch>,
+	execution: sc_cli::ExecutionStrategy,
+) -> sc_cli::Result<()> {
+	let (changes, weight, _, _) = execute_upgrade::<Block, ExecDispatch>(
+		ext,
+		executor,
+		execution,
+		upgrade_extensions(command),
+		command.no_proof,
+		command.max_storage_reads,
+		None,
+		None,
+	)?;
+	ensure_unchanged(
+		ext,
+		&changes,
+		&command.run_twice_allowed_prefix,
+		"second execution of the upgrade",
+	)?;
+	let what = "second execution of the upgrade";
+	ensure_trivial_weight(what, weight, command.run_twice_max_weight, "--run-twice-max-weight")?;
+	if weight != Weight::zero() {
+		log::warn!(
+			target: LOG_TARGET,
+			"second execution of the upgrade changed no storage, but consumed weight ({} ps, {} byte)",
+			weight.ref_time(),
+			weight.proof_size(),
+		);
+	}
+	Ok(())
+}
+
+/// Fail if the `weight` consumed by `what`, an upgrade that should do nothing, exceeds
+/// `max_ref_time`, which is set by the flag `max_flag`.
+fn ensure_trivial_weight(
+	what: &str,
+	weight: Weight,
+	max_ref_time: u64,
+	max_flag: &str,
+) -> sc_cli::Result<()> {
+	if weight.ref_time() > max_ref_time {
+		return Err(format!(
+			"{} consumed {} ps, more than {} {} ps",
+			what,
+			weight.ref_time(),
+			max_flag,
+			max_ref_time,
+		)
+		.into())
+	}
+	Ok(())
+}
+
+/// Write `spec`, with the state of `ext` as its genesis storage, to `path` as a raw chain spec.
+fn export_chain_spec(
+	mut spec: Box<dyn ChainSpec>,
+	ext: &TestExternalities,
+	path: &Path,
+) -> sc_cli::Result<()> {
+	spec.set_storage(storage_of(ext)?);
+	std::fs::write(path, spec.as_json(true)?)?;
+	Ok(())
+}
+
+/// How often posting to [`OnRuntimeUpgradeCmd::webhook`] is retried.
+const WEBHOOK_RETRIES: u32 = 3;
+
+/// POST `results` to the [`OnRuntimeUpgradeCmd::webhook`], if set.
+///
+/// A failure is logged, but does not change the outcome of the command.
+async fn notify_webhook(
+	command: &OnRuntimeUpgradeCmd,
+	results: &[(&State, sc_cli::Result<UpgradeSummary>)],
+) {
+	let url = match &command.webhook {
+		Some(url) => url,
+		None => return,
+	};
+	let body = results_json(results).to_string();
+	match webhook::post_json(url, &body, WEBHOOK_RETRIES, Duration::from_secs(1)).await {
+		Ok(()) => log::info!(target: LOG_TARGET, "posted the results to the webhook"),
+		Err(e) => log::error!(target: LOG_TARGET, "{}", e),
+	}
+}
+
+/// The summary line of [`OnRuntimeUpgradeCmd::ci_summary`].
+fn ci_summary(results: &[(&State, sc_cli::Result<UpgradeSummary>)]) -> String {
+	let passed = results.iter().all(|(_, result)| result.is_ok());
+	let max_weight = results
+		.iter()
+		.filter_map(|(_, result)| result.as_ref().ok())
+		.fold(Weight::zero(), |max, summary| max.max(summary.weight));
+	format!(
+		"TRY_RUNTIME_RESULT status={} ref_time={} proof_size={} checks={}",
+		if passed { "ok" } else { "fail" },
+		max_weight.ref_time(),
+		max_weight.proof_size(),
+		if passed { "pass" } else { "fail" },
+	)
+}
+
+/// The JSON payload of [`OnRuntimeUpgradeCmd::webhook`].
+fn results_json(results: &[(&State, sc_cli::Result<UpgradeSummary>)]) -> serde_json::Value {
+	let weight = |weight: Weight| {
+		serde_json::json!({ "ref_time": weight.ref_time(), "proof_size": weight.proof_size() })
+	};
+	let states = results
+		.iter()
+		.map(|(state, result)| match result {
+			Ok(summary) => serde_json::json!({
+				"state": format!("{:?}", state),
+				"ok": true,
+				"weight": weight(summary.weight),
+				"total_weight": weight(summary.total_weight),
+				"overlay_size": summary.overlay_size,
+			}),
+			Err(e) => serde_json::json!({
+				"state": format!("{:?}", state),
+				"ok": false,
+				"error": e.to_string(),
+			}),
+		})
+		.collect::<Vec<_>>();
+	let failed = results.iter().filter(|(_, result)| result.is_err()).count();
+	serde_json::json!({ "states": states, "failed": failed })
+}
+
+/// Read the wasm runtime at `path`, as the value of the `:code` key.
+fn runtime_code(path: &Path) -> sc_cli::Result<(StorageKey, StorageData)> {
+	let code = std::fs::read(path)
+		.map_err(|e| format!("failed to read the runtime {}: {}", path.display(), e))?;
+	Ok((StorageKey(well_known_keys::CODE.to_vec()), StorageData(code)))
+}
+
+/// Upgrade `ext` to each of the `runtimes` in turn with `execute`, applying the changes of each
+/// upgrade, and then set its runtime to `final_code`.
+///
+/// Returns the weight consumed by each upgrade.
+fn chain_upgrades(
+	ext: &mut TestExternalities,
+	runtimes: &[PathBuf],
+	final_code: &StorageData,
+	state_version: StateVersion,
+	mut execute: impl FnMut(&TestExternalities) -> sc_cli::Result<(OverlayedChanges, Weight)>,
+) -> sc_cli::Result<Vec<Weight>> {
+	if runtimes.is_empty() {
+		return Ok(Vec::new())
+	}
+
+	let mut weights = Vec::with_capacity(runtimes.len());
+	for path in runtimes {
+		let (key, code) = runtime_code(path)?;
+		ext.insert(key.0, code.0);
+		ext.commit_all()?;
+
+		let (mut changes, weight) = execute(ext)?;
+		apply_changes(ext, &mut changes, state_version)?;
+		log::info!(
+			target: LOG_TARGET,
+			"upgrade to {} consumed weight ({} ps, {} byte)",
+			path.display(),
+			weight.ref_time(),
+			weight.proof_size(),
+		);
+		weights.push(weight);
+	}
+
+	ext.insert(well_known_keys::CODE.to_vec(), final_code.0.clone());
+	ext.commit_all()?;
+	Ok(weights)
+}
+
+/// The externalities extensions that `command` asks to provide to the upgrade.
+fn upgrade_extensions(command: &OnRuntimeUpgradeCmd) -> Extensions {
+	let mut extensions = Extensions::default();
+	if command.with_offchain || command.freeze_time.is_some() {
+		let (offchain, offchain_state) = TestOffchainExt::new();
+		if let Some(millis) = command.freeze_time {
+			offchain_state.write().timestamp = Timestamp::from_unix_millis(millis);
+		}
+		extensions.register(OffchainDbExt::new(offchain.clone()));
+		extensions.register(OffchainWorkerExt::new(offchain));
+	}
+	if command.with_keystore {
+		extensions.register(KeystoreExt(Arc::new(KeyStore::new())));
+	}
+	extensions
+}
+
+/// Run `f` on a dedicated thread, failing with a timeout error if it does not finish within
+/// `budget`.
+///
+/// `f` cannot be interrupted, so on timeout its thread is abandoned and runs on until `f` finishes
+/// or the process exits. Unlike a `tokio::task::spawn_blocking` task, which the runtime waits for
+/// when it is dropped, the thread does not keep the process from exiting.
+async fn within_time_budget<T: Send + 'static>(
+	budget: Duration,
+	f: impl FnOnce() -> T + Send + 'static,
+) -> sc_cli::Result<T> {
+	let started = Instant::now();
+	let (tx, rx) = tokio::sync::oneshot::channel();
+	std::thread::spawn(move || {
+		// the receiver is gone if the budget was exceeded.
+		let _ = tx.send(f());
+	});
+	match tokio::time::timeout(budget, rx).await {
+		Ok(Ok(result)) => Ok(result),
+		Ok(Err(_)) => Err("the upgrade thread panicked".into()),
+		Err(_) => Err(format!(
+			"timeout: the upgrade did not finish within --time-budget of {:?}, aborted after {:?}",
+			budget,
+			started.elapsed(),
+		)
+		.into()),
+	}
+}
+
+/// Execute `TryRuntime_on_runtime_upgrade` on top of `ext`, returning the state changes, the
+/// consumed weight, the total allowed block weight and the database operations performed.
+///
+/// The storage proof of the call is recorded and reported unless `no_proof` is set. The call is
+/// aborted after `max_storage_reads` reads, if set. If `dump_raw_result` is set, the encoded
+/// result is written there, see [`OnRuntimeUpgradeCmd::dump_raw_result`]. If `node_access_log`
+/// is set, the trie nodes fetched by the call are written there, see
+/// [`OnRuntimeUpgradeCmd::node_access_log`]; this needs the proof to be recorded.
+fn execute_upgrade<Block: BlockT, ExecDispatch: NativeExecutionDispatch + 'static>(
+	ext: &TestExternalities,
+	executor: &NativeElseWasmExecutor<ExecDispatch>,
+	execution: sc_cli::ExecutionStrategy,
+	extensions: Extensions,
+	no_proof: bool,
+	max_storage_reads: Option<u64>,
+	dump_raw_result: Option<&str>,
+	node_access_log: Option<&Path>,
+) -> sc_cli::Result<(OverlayedChanges, Weight, Weight, DbOpStats)> {
+	let method = "TryRuntime_on_runtime_upgrade";
+	let (changes, encoded_result, db_stats) = if no_proof {
+		state_machine_call_with_db_stats::<Block, ExecDispatch>(
+			ext,
+			executor,
+			execution,
+			method,
+			&[],
+			extensions,
+			max_storage_reads,
+		)?
+	} else {
+		let mut node_accesses = Vec::new();
+		let result = state_machine_call_with_proof::<Block, ExecDispatch>(
+			ext,
+			executor,
+			execution,
+			method,
+			&[],
+			extensions,
+			max_storage_reads,
+			node_access_log.map(|_| &mut node_accesses),
+		)?;
+		if let Some(path) = node_access_log {
+			let log = node_accesses
+				.iter()
+				.map(|hash| format!("0x{}\n", HexDisplay::from(&hash.as_ref())))
+				.collect::<String>();
+			std::fs::write(path, log)?;
+			log::info!(
+				target: LOG_TARGET,
+				"wrote the {} trie node accesses of the upgrade to {:?}",
+				node_accesses.len(),
+				path,
+			);
+		}
+		result
+	};
+
+	if let Some(target) = dump_raw_result {
+		write_raw_result(target, &encoded_result)?;
+	}
+
+	let (weight, total_weight) = <(Weight, Weight) as Decode>::decode(&mut &*encoded_result)
+		.map_err(|e| format!("failed to decode weight: {:?}", e))?;
+	Ok((changes, weight, total_weight, db_stats))
+}
+
+/// Execute `TryRuntime_on_runtime_upgrade` on top of `ext` with an uncached wasm executor,
+/// returning the result of the call and the allocation statistics of the wasm heap.
+///
+/// The state changes of the call are discarded.
+fn wasm_upgrade_with_allocation_stats<ExecDispatch: NativeExecutionDispatch + 'static>(
+	ext: &TestExternalities,
+	shared: &SharedParams,
+	config: &Configuration,
+	mut extensions: Extensions,
+) -> sc_cli::Result<(Result<Vec<u8>, sc_executor::error::Error>, Option<AllocationStats>)> {
+	let executor = WasmExecutor::<
+		ExtendedHostFunctions<sp_io::SubstrateHostFunctions, ExecDispatch::ExtendHostFunctions>,
+	>::new(
+		execution_method_from_cli(shared.wasm_method, shared.wasmtime_instantiation_strategy),
+		shared.heap_pages.or(config.default_heap_pages),
+		1,
+		None,
+		1,
+	);
+	let code = BackendRuntimeCode::new(&ext.backend)
+		.fetch_runtime_code()
+		.ok_or("the state has no runtime code")?
+		.into_owned();
+
+	extensions.register(TaskExecutorExt::new(TaskExecutor::new()));
+	let mut changes = OverlayedChanges::default();
+	let mut cache = StorageTransactionCache::default();
+	let mut wasm_ext = Ext::new(&mut changes, &mut cache, &ext.backend, Some(&mut extensions));
+	Ok(executor.call_with_allocation_stats(
+		&code,
+		&mut wasm_ext,
+		"TryRuntime_on_runtime_upgrade",
+		&[],
+	))
+}
+
+/// The peak number of bytes allocated on the wasm heap by `call`, which returns the result of the
+/// upgrade and the allocation statistics of its execution.
+fn measure_heap_peak(
+	call: impl FnOnce() -> sc_cli::Result<(
+		Result<Vec<u8>, sc_executor::error::Error>,
+		Option<AllocationStats>,
+	)>,
+) -> sc_cli::Result<u32> {
+	let (result, stats) = call()?;
+	result.map_err(|e| format!("failed to execute the upgrade in wasm: {}", e))?;
+	let stats = stats.ok_or("the wasm executor did not report the allocation statistics")?;
+	Ok(stats.bytes_allocated_peak)
+}
+
+/// Write `encoded` as hex to the file at `target`, or to the standard output if it is `stdout`.
+fn write_raw_result(target: &str, encoded: &[u8]) -> sc_cli::Result<()> {
+	write_to(target, &format!("0x{}", HexDisplay::from(&encoded)))
+}
+
+/// Write `content` to the file at `target`, or to the standard output if it is `stdout`.
+fn write_to(target: &str, content: &str) -> sc_cli::Result<()> {
+	if target == "stdout" {
+		println!("{}", content);
+	} else {
+		std::fs::write(target, content)?;
+	}
+	Ok(())
+}
+
+/// The number of events deposited by the upgrade and their concatenated SCALE encoding, given
+/// the encoded `System::Events` before and after it.
+///
+/// Events are appended to `System::Events`, so the new ones follow the events already there.
+fn new_events(before: Option<&[u8]>, after: Option<&[u8]>) -> sc_cli::Result<(u32, Vec<u8>)> {
+	let split = |encoded: Option<&[u8]>| -> sc_cli::Result<(u32, &[u8])> {
+		let mut input = match encoded {
+			Some(encoded) => encoded,
+			None => return Ok((0, &[])),
+		};
+		let count = <Compact<u32> as Decode>::decode(&mut input)
+			.map_err(|e| format!("failed to decode System::Events: {}", e))?;
+		Ok((count.0, input))
+	};
+	let (count_before, events_before) = split(before)?;
+	let (count_after, events_after) = split(after)?;
+
+	match events_after.strip_prefix(events_before) {
+		Some(new) if count_after >= count_before => Ok((count_after - count_before, new.to_vec())),
+		_ => Err("System::Events was modified by the upgrade, not only appended to".into()),
+	}
+}
+
+/// Insert `keys` into `ext`, and commit them to its backend.
+fn inject_keys(
+	ext: &mut TestExternalities,
+	keys: Vec<(StorageKey, StorageData)>,
+) -> sc_cli::Result<()> {
+	for (key, value) in keys {
+		ext.insert(key.0, value.0);
+	}
+	ext.commit_all()?;
+	Ok(())
+}
+
+/// The runtime to upgrade to: `local_code` if set, or else the `:code` of `ext`.
+fn target_code(
+	ext: &TestExternalities,
+	local_code: Option<(StorageKey, StorageData)>,
+) -> sc_cli::Result<(StorageKey, StorageData)> {
+	if let Some(code) = local_code {
+		return Ok(code)
+	}
+	let code = ext
+		.backend
+		.storage(well_known_keys::CODE)
+		.map_err(|e| format!("failed to read :code: {}", e))?
+		.ok_or("the remote state has no :code")?;
+	Ok((StorageKey(well_known_keys::CODE.to_vec()), StorageData(code)))
+}
+
+/// Fail if the storage root of `ext` is not `expected`.
+fn ensure_state_root(ext: &TestExternalities, expected: &[u8]) -> sc_cli::Result<()> {
+	let root = ext.backend.root().as_ref();
+	if root != expected {
+		return Err(format!(
+			"the fetched state has storage root 0x{}, but the node reports 0x{}, the state is \
+			incomplete or --state-version does not match the chain",
+			HexDisplay::from(&root),
+			HexDisplay::from(&expected),
+		)
+		.into())
+	}
+	log::info!(target: LOG_TARGET, "the fetched state matches the state root reported by the node");
+	Ok(())
+}
+
+/// Fail if `changes` change any key of `ext` outside of `allowed_prefixes`, blaming `what`.
+fn ensure_unchanged(
+	ext: &TestExternalities,
+	changes: &OverlayedChanges,
+	allowed_prefixes: &[Vec<u8>],
+	what: &str,
+) -> sc_cli::Result<()> {
+	let changed = changed_keys(ext, changes)?
+		.into_iter()
+		.filter(|key| !allowed_prefixes.iter().any(|prefix| key.starts_with(prefix)))
+		.map(|key| format!("0x{}", HexDisplay::from(&key)))
+		.collect::<Vec<_>>();
+	if !changed.is_empty() {
+		return Err(format!(
+			"{} changed {} storage keys, is a storage version check missing? {:?}",
+			what,
+			changed.len(),
+			changed,
+		)
+		.into())
+	}
+	Ok(())
+}
+
+/// The keys, including prefixed child trie keys, whose value in `changes` differs from `ext`.
+fn changed_keys(
+	ext: &TestExternalities,
+	changes: &OverlayedChanges,
+) -> sc_cli::Result<Vec<Vec<u8>>> {
+	keys_where(ext, changes, |before, after| before != after)
+}
+
+/// The keys, including prefixed child trie keys, that exist in `ext` but are removed by `changes`.
+fn removed_keys(
+	ext: &TestExternalities,
+	changes: &OverlayedChanges,
+) -> sc_cli::Result<Vec<Vec<u8>>> {
+	keys_where(ext, changes, |before, after| before.is_some() && after.is_none())
+}
+
+/// The keys in `changes` for which `predicate` holds, given the value in `ext` and in `changes`.
+fn keys_where(
+	ext: &TestExternalities,
+	changes: &OverlayedChanges,
+	predicate: impl Fn(Option<&StorageValue>, Option<&StorageValue>) -> bool,
+) -> sc_cli::Result<Vec<Vec<u8>>> {
+	let mut keys = Vec::new();
+	for (key, value) in changes.changes() {
+		let current =
+			ext.backend.storage(key).map_err(|e| format!("failed to read storage: {}", e))?;
+		if predicate(current.as_ref(), value.value()) {
+			keys.push(key.clone());
+		}
+	}
+	for (child_changes, child_info) in changes.children() {
+		for (key, value) in child_changes {
+			let current = ext
+				.backend
+				.child_storage(child_info, key)
+				.map_err(|e| format!("failed to read child storage: {}", e))?;
+			if predicate(current.as_ref(), value.value()) {
+				keys.push([child_info.prefixed_storage_key().into_inner(), key.clone()].concat());
+			}
+		}
+	}
+	Ok(keys)
+}
+
+/// The postfix of the storage key of the storage version of a pallet, see
+/// `frame_support::traits::StorageVersion`.
+const STORAGE_VERSION_STORAGE_KEY_POSTFIX: &[u8] = b":__STORAGE_VERSION__:";
+
+/// A change of the storage version of a pallet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct VersionChange {
+	/// The `twox_128` hash of the name of the pallet.
+	pallet_prefix: Vec<u8>,
+	/// The version before the upgrade, if set.
+	old: Option<u16>,
+	/// The version after the upgrade, if set.
+	new: Option<u16>,
+}
+
+/// The storage versions of pallets that `changes` change in `ext`.
+fn version_changes(
+	ext: &TestExternalities,
+	changes: &OverlayedChanges,
+) -> sc_cli::Result<Vec<VersionChange>> {
+	let postfix = twox_128(STORAGE_VERSION_STORAGE_KEY_POSTFIX);
+	let decode = |value: Option<&StorageValue>| value.and_then(|v| u16::decode(&mut &v[..]).ok());
+
+	let mut version_changes = Vec::new();
+	for (key, value) in changes.changes() {
+		if key.len() != 32 || key[16..] != postfix {
+			continue
+		}
+		let old = ext.backend.storage(key).map_err(|e| format!("failed to read storage: {}", e))?;
+		let (old, new) = (decode(old.as_ref()), decode(value.value()));
+		if old != new {
+			version_changes.push(VersionChange { pallet_prefix: key[..16].to_vec(), old, new });
+		}
+	}
+	Ok(version_changes)
+}
+
+/// Format `version_changes` as a table, one pallet per line, naming the pallets among
+/// `pallet_names`.
+fn version_changes_table(version_changes: &[VersionChange], pallet_names: &[String]) -> String {
+	if version_changes.is_empty() {
+		return "  (none)".into()
+	}
+	let version = |version: Option<u16>| version.map_or("-".into(), |v| v.to_string());
+	version_changes
+		.iter()
+		.map(|change| {
+			let pallet = pallet_names
+				.iter()
+				.find(|name| twox_128(name.as_bytes())[..] == change.pallet_prefix[..])
+				.cloned()
+				.unwrap_or_else(|| format!("0x{}", HexDisplay::from(&change.pallet_prefix)));
+			format!("  {:<34} {} -> {}", pallet, version(change.old), version(change.new))
+		})
+		.collect::<Vec<_>>()
+		.join("\n")
+}
+
+/// A warning if the `consumed` weight exceeds the `declared` weight in any dimension.
+fn under_declaration(consumed: Weight, declared: Weight) -> Option<String> {
+	if !consumed.any_gt(declared) {
+		return None
+	}
+	Some(format!(
+		"UNDER-DECLARED WEIGHT: the migrations consumed ({} ps, {} byte), but declare only ({} ps, \
+		{} byte), blocks with this upgrade may be overweight",
+		consumed.ref_time(),
+		consumed.proof_size(),
+		declared.ref_time(),
+		declared.proof_size(),
+	))
+}
+
+/// Format the `declared` and `consumed` weight as a table, one dimension per line, with the
+/// signed delta of consumed over declared.
+///
+/// A positive delta means the dimension is under-declared.
+fn weight_delta_table(consumed: Weight, declared: Weight) -> String {
+	let row = |dimension: &str, declared: u64, consumed: u64| {
+		format!(
+			"  {:<12} {:>20} {:>20} {:>+21}",
+			dimension,
+			declared,
+			consumed,
+			consumed as i128 - declared as i128,
+		)
+	};
+	[
+		format!("  {:<12} {:>20} {:>20} {:>21}", "dimension", "declared", "consumed", "delta"),
+		row("ref_time", declared.ref_time(), consumed.ref_time()),
+		row("proof_size", declared.proof_size(), consumed.proof_size()),
+	]
+	.join("\n")
+}
+
+/// Fail if `encoded`, the SCALE encoded `Result<(), Vec<u8>>` returned by `method`, is an error.
+fn ensure_valid(method: &str, encoded: &[u8]) -> sc_cli::Result<()> {
+	let result = <Result<(), Vec<u8>> as Decode>::decode(&mut &*encoded)
+		.map_err(|e| format!("failed to decode the result of {}: {:?}", method, e))?;
+	result.map_err(|error| {
+		let error = match String::from_utf8(error) {
+			Ok(error) => error,
+			Err(e) => format!("0x{}", HexDisplay::from(&e.into_bytes())),
+		};
+		format!("{} rejected the state after the upgrade: {}", method, error).into()
+	})
+}
+
+/// Call each of the runtime apis of `calls` with its SCALE encoded arguments on top of `ext`,
+/// logging the SCALE encoded results.
+fn runtime_api_calls<Block: BlockT, ExecDispatch: NativeExecutionDispatch + 'static>(
+	ext: &TestExternalities,
+	executor: &NativeElseWasmExecutor<ExecDispatch>,
+	execution: sc_cli::ExecutionStrategy,
+	calls: &[(String, Vec<u8>)],
+) -> sc_cli::Result<Vec<Vec<u8>>> {
+	forward_runtime_api_calls(calls, |method, args| {
+		state_machine_call_with_proof::<Block, ExecDispatch>(
+			ext,
+			executor,
+			execution,
+			method,
+			args,
+			Default::default(),
+			None,
+			None,
+		)
+		.map(|(_, encoded_result, _)| encoded_result)
+	})
+}
+
+/// Execute each of `calls` with `call`, which gets the method and its SCALE encoded arguments,
+/// logging the SCALE encoded results.
+fn forward_runtime_api_calls(
+	calls: &[(String, Vec<u8>)],
+	mut call: impl FnMut(&str, &[u8]) -> sc_cli::Result<Vec<u8>>,
+) -> sc_cli::Result<Vec<Vec<u8>>> {
+	calls
+		.iter()
+		.map(|(method, args)| {
+			let encoded_result = call(method, args)?;
+			log::info!(
+				target: LOG_TARGET,
+				"{} returned 0x{}",
+				method,
+				HexDisplay::from(&encoded_result),
+			);
+			Ok(encoded_result)
+		})
+		.collect()
+}
+
+/// Collect the entire state of `ext`, including the default child tries, into a [`Storage`].
+fn storage_of(ext: &TestExternalities) -> sc_cli::Result<Storage> {
+	let mut storage = Storage::default();
+	for (key, value) in ext.backend.pairs() {
+		let storage_key = match key.strip_prefix(well_known_keys::DEFAULT_CHILD_STORAGE_KEY_PREFIX) {
+			Some(storage_key) => storage_key,
+			None => {
+				storage.top.insert(key, value);
+				continue
+			},
+		};
+
+		let child_info = ChildInfo::new_default(storage_key);
+		let mut data = std::collections::BTreeMap::new();
+		for child_key in ext.backend.child_keys(&child_info, &[]) {
+			let child_value = ext
+				.backend
+				.child_storage(&child_info, &child_key)
+				.map_err(|e| format!("failed to read child storage: {}", e))?
+				.expect("key was just listed by the same backend; qed");
+			data.insert(child_key, child_value);
+		}
+		storage
+			.children_default
+			.insert(storage_key.to_vec(), StorageChild { data, child_info });
+	}
+	Ok(storage)
+}
+
+/// Commit `changes` into the backend of `ext`.
+fn apply_changes(
+	ext: &mut TestExternalities,
+	changes: &mut OverlayedChanges,
+	state_version: StateVersion,
+) -> sc_cli::Result<()> {
+	let storage_changes = changes
+		.drain_storage_changes(&ext.backend, &mut Default::default(), state_version)
+		.map_err(|e| format!("failed to drain storage changes: {}", e))?;
+	ext.backend
+		.apply_transaction(storage_changes.transaction_storage_root, storage_changes.transaction);
+	Ok(())
+}
+
+/// Ensure that at least `count` keys start with `prefix` in `ext`, for each of `min_keys`.
+fn ensure_min_keys(ext: &TestExternalities, min_keys: &[(Vec<u8>, usize)]) -> sc_cli::Result<()> {
+	let violations = min_keys
+		.iter()
+		.filter_map(|(prefix, min)| {
+			let count = ext.backend.keys(prefix).len();
+			(count < *min).then(|| {
+				format!("0x{}: {} keys, expected {}", HexDisplay::from(prefix), count, min)
+			})
+		})
+		.collect::<Vec<_>>();
+	if !violations.is_empty() {
+		return Err(format!("too few keys after the upgrade, {}", violations.join("; ")).into())
+	}
+	Ok(())
+}
+
+/// The storage values that set up the block context requested by `command`.
+fn mocked_block_context<Block: BlockT>(
+	command: &OnRuntimeUpgradeCmd,
+) -> Vec<(StorageKey, StorageData)> {
+	let storage_value = |pallet: &[u8], item: &[u8], value: Vec<u8>| {
+		(StorageKey([twox_128(pallet), twox_128(item)].concat()), StorageData(value))
+	};
+
+	let mut context = Vec::new();
+	if let Some(timestamp) = command.mock_timestamp {
+		context.push(storage_value(b"Timestamp", b"Now", timestamp.encode()));
+	}
+	if let Some(ref author) = command.mock_author {
+		context.push(storage_value(b"Authorship", b"Author", author.clone()));
+	}
+	if let Some(number) = command.at_block_number {
+		let number = NumberFor::<Block>::from(number);
+		context.push(storage_value(b"System", b"Number", number.encode()));
+	}
+	context
+}
+
+const MEGABYTE: usize = 1024 * 1024;
+
+/// The total size of the keys and values, including child tries, held in `changes`.
+fn overlay_size(changes: &OverlayedChanges) -> usize {
+	let top = changes
+		.changes()
+		.map(|(key, value)| key.len() + value.value().map_or(0, |v| v.len()))
+		.sum::<usize>();
+	let children = changes
+		.children()
+		.flat_map(|(child_changes, _)| child_changes)
+		.map(|(key, value)| key.len() + value.value().map_or(0, |v| v.len()))
+		.sum::<usize>();
+	top + children
+}
+
+/// The number of keys and the total size of the keys and values of a state.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct StateSize {
+	keys: u64,
+	bytes: u64,
+}
+
+impl StateSize {
+	fn add(&mut self, key: &[u8], value: &[u8]) {
+		self.keys += 1;
+		self.bytes += (key.len() + value.len()) as u64;
+	}
+
+	fn remove(&mut self, key: &[u8], value: &[u8]) {
+		self.keys = self.keys.saturating_sub(1);
+		self.bytes = self.bytes.saturating_sub((key.len() + value.len()) as u64);
+	}
+}
+
+/// The size of the state of `ext`, including the default child tries, before and after applying
+/// `changes` to it.
+fn state_sizes(
+	ext: &TestExternalities,
+	changes: &OverlayedChanges,
+) -> sc_cli::Result<(StateSize, StateSize)> {
+	let storage = storage_of(ext)?;
+	let mut before = StateSize::default();
+	for (key, value) in storage
+		.top
+		.iter()
+		.chain(storage.children_default.values().flat_map(|child| &child.data))
+	{
+		before.add(key, value);
+	}
+
+	let mut after = before;
+	let mut apply = |old: Option<&StorageValue>, key: &[u8], new: Option<&StorageValue>| {
+		if let Some(old) = old {
+			after.remove(key, old);
+		}
+		if let Some(new) = new {
+			after.add(key, new);
+		}
+	};
+	for (key, value) in changes.changes() {
+		apply(storage.top.get(key), key, value.value());
+	}
+	for (child_changes, child_info) in changes.children() {
+		let data = storage.children_default.get(child_info.storage_key()).map(|child| &child.data);
+		for (key, value) in child_changes {
+			apply(data.and_then(|data| data.get(key)), key, value.value());
+		}
+	}
+	Ok((before, after))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn storage_of_includes_child_tries() {
+		let mut storage = Storage::default();
+		storage.top.insert(b"key".to_vec(), b"value".to_vec());
+		storage.children_default.insert(
+			b"child".to_vec(),
+			StorageChild {
+				data: [(b"child_key".to_vec(), b"child_value".to_vec())].into_iter().collect(),
+				child_info: ChildInfo::new_default(b"child"),
+			},
+		);
+		let ext = TestExternalities::new(storage.clone());
+
+		let exported = storage_of(&ext).unwrap();
+		assert_eq!(exported.top.get(&b"key".to_vec()), Some(&b"value".to_vec()));
+		assert!(!exported.top.keys().any(|key| well_known_keys::is_child_storage_key(key)));
+		assert_eq!(exported.children_default.len(), 1);
+		assert_eq!(
+			exported.children_default[&b"child".to_vec()].data,
+			storage.children_default[&b"child".to_vec()].data,
+		);
+	}
+
+	#[test]
+	fn exported_chain_spec_has_the_upgraded_state() {
+		use sc_chain_spec::{ChainType, GenericChainSpec, NoExtension};
+		use sp_runtime::BuildStorage;
+
+		let mut storage = Storage::default();
+		storage.top.insert(b"old_key".to_vec(), b"value".to_vec());
+		let mut ext = TestExternalities::new(storage);
+		// a migration that moves a value to a new key.
+		let mut changes = OverlayedChanges::default();
+		changes.set_storage(b"old_key".to_vec(), None);
+		changes.set_storage(b"new_key".to_vec(), Some(b"migrated".to_vec()));
+		apply_changes(&mut ext, &mut changes, StateVersion::V1).unwrap();
+
+		let spec = GenericChainSpec::<()>::from_genesis(
+			"Local",
+			"local",
+			ChainType::Local,
+			|| (),
+			Vec::new(),
+			None,
+			None,
+			None,
+			None,
+			NoExtension::None,
+		);
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("upgraded.json");
+		export_chain_spec(Box::new(spec), &ext, &path).unwrap();
+
+		let exported = GenericChainSpec::<()>::from_json_bytes(std::fs::read(&path).unwrap())
+			.unwrap()
+			.build_storage()
+			.unwrap();
+		assert_eq!(exported.top.get(&b"new_key".to_vec()), Some(&b"migrated".to_vec()));
+		assert!(!exported.top.contains_key(&b"old_key".to_vec()));
+	}
+
+	#[test]
+	fn state_sizes_account_for_the_changes() {
+		let mut storage = Storage::default();
+		storage.top.insert(b"key".to_vec(), b"value".to_vec());
+		storage.top.insert(b"removed".to_vec(), b"value".to_vec());
+		storage.children_default.insert(
+			b"child".to_vec(),
+			StorageChild {
+				data: [(b"child_key".to_vec(), b"child_value".to_vec())].into_iter().collect(),
+				child_info: ChildInfo::new_default(b"child"),
+			},
+		);
+		let ext = TestExternalities::new(storage);
+
+		// a migration that grows a value, removes a key and adds two.
+		let mut changes = OverlayedChanges::default();
+		changes.set_storage(b"key".to_vec(), Some(b"longer value".to_vec()));
+		changes.set_storage(b"removed".to_vec(), None);
+		changes.set_storage(b"added".to_vec(), Some(vec![0; 100]));
+		changes.set_storage(b"added2".to_vec(), Some(vec![0]));
+
+		let (before, after) = state_sizes(&ext, &changes).unwrap();
+		assert!(before.bytes >= (3 + 5) + (7 + 5) + (9 + 11));
+		assert_eq!(after.keys, before.keys + 1);
+		assert_eq!(after.bytes - before.bytes, (12 - 5) - (7 + 5) + (5 + 100) + (6 + 1));
+	}
+
+	#[test]
+	fn overlay_size_counts_keys_and_values() {
+		let mut changes = OverlayedChanges::default();
+		assert_eq!(overlay_size(&changes), 0);
+
+		changes.set_storage(vec![1; 32], Some(vec![0; MEGABYTE]));
+		changes.set_storage(vec![2; 32], None);
+		assert_eq!(overlay_size(&changes), 64 + MEGABYTE);
+	}
+
+	#[test]
+	fn overlay_above_the_limit_fails_the_command() {
+		// a migration that writes 2 MB of data.
+		let mut changes = OverlayedChanges::default();
+		for i in 0..32u8 {
+			changes.set_storage(vec![i; 32], Some(vec![i; 64 * 1024]));
+		}
+		let size = 32 * (32 + 64 * 1024);
+
+		let err = check_overlay_size(&changes, Some(1)).unwrap_err();
+		assert!(err.to_string().contains("exceeds --max-overlay-mb 1"), "{}", err);
+		assert_eq!(check_overlay_size(&changes, Some(3)).unwrap(), size);
+		assert_eq!(check_overlay_size(&changes, None).unwrap(), size);
+	}
+
+	#[test]
+	fn changed_keys_ignores_identical_writes() {
+		let mut ext = TestExternalities::default();
+		ext.insert(b"same".to_vec(), b"value".to_vec());
+		ext.insert(b"other".to_vec(), b"old".to_vec());
+		ext.commit_all().unwrap();
+
+		let mut changes = OverlayedChanges::default();
+		changes.set_storage(b"same".to_vec(), Some(b"value".to_vec()));
+		changes.set_storage(b"other".to_vec(), Some(b"new".to_vec()));
+		changes.set_storage(b"fresh".to_vec(), Some(b"value".to_vec()));
+
+		let mut changed = changed_keys(&ext, &changes).unwrap();
+		changed.sort();
+		assert_eq!(changed, vec![b"fresh".to_vec(), b"other".to_vec()]);
+	}
+
+	#[test]
+	fn ensure_unchanged_detects_missing_version_check() {
+		let mut ext = TestExternalities::default();
+		ext.insert(b"version".to_vec(), vec![1]);
+		ext.insert(b"item".to_vec(), b"migrated".to_vec());
+		ext.commit_all().unwrap();
+
+		// a guarded migration only reads the storage version.
+		let changes = OverlayedChanges::default();
+		assert!(ensure_unchanged(&ext, &changes, &[], "upgrade").is_ok());
+
+		// an unguarded one migrates the items again.
+		let mut changes = OverlayedChanges::default();
+		changes.set_storage(b"item".to_vec(), Some(b"migrated twice".to_vec()));
+		changes.set_storage(b"version".to_vec(), Some(vec![1]));
+		let err = ensure_unchanged(&ext, &changes, &[], "upgrade").unwrap_err();
+		assert!(err.to_string().contains("upgrade changed 1 storage keys"));
+		assert!(ensure_unchanged(&ext, &changes, &[b"it".to_vec()], "upgrade").is_ok());
+	}
+
+	#[test]
+	fn weight_without_proof_omits_the_proof_size() {
+		use clap::Parser;
+		let command = OnRuntimeUpgradeCmd::parse_from([
+			"on-runtime-upgrade",
+			"--no-proof",
+			"snap",
+			"-s",
+			"state.snap",
+		]);
+		let weight = Weight::from_parts(250, 1234);
+		let total_weight = Weight::from_parts(1000, 5678);
+
+		let summary = weight_summary(weight, total_weight, command.no_proof);
+		assert_eq!(
+			summary,
+			"Consumed weight = 250 ps, total weight = 1000 ps (25.00 %), proof size not recorded.",
+		);
+		assert!(!summary.contains("byte"));
+
+		let summary = weight_summary(weight, total_weight, false);
+		assert!(summary.starts_with("Consumed weight = (250 ps, 1234 byte)"), "{}", summary);
+
+		// no trie node accesses can be exported without the proof.
+		assert!(OnRuntimeUpgradeCmd::try_parse_from([
+			"on-runtime-upgrade",
+			"--no-proof",
+			"--node-access-log",
+			"nodes.log",
+			"snap",
+			"-s",
+			"state.snap",
+		])
+		.is_err());
+	}
+
+	#[test]
+	fn second_run_above_the_weight_limit_fails() {
+		use clap::Parser;
+		let command = OnRuntimeUpgradeCmd::parse_from([
+			"on-runtime-upgrade",
+			"--run-twice",
+			"snap",
+			"-s",
+			"state.snap",
+		]);
+
+		let check = |weight, max| ensure_trivial_weight("second run", weight, max, "--max");
+
+		// a guarded migration reads its storage version.
+		let read = Weight::from_ref_time(25_000_000);
+		assert!(check(read, command.run_twice_max_weight).is_ok());
+		assert!(check(Weight::zero(), command.run_twice_max_weight).is_ok());
+
+		// an unguarded one does all its work again.
+		let migration = Weight::from_ref_time(5_000_000_000);
+		let err = check(migration, command.run_twice_max_weight).unwrap_err();
+		assert!(err.to_string().contains("second run consumed 5000000000 ps"), "{}", err);
+		assert!(check(migration, 10_000_000_000).is_ok());
+	}
+
+	#[test]
+	fn noop_upgrade_above_the_weight_limit_fails() {
+		use clap::Parser;
+		let command = OnRuntimeUpgradeCmd::parse_from([
+			"on-runtime-upgrade",
+			"--assert-noop-on-migrated",
+			"--max-noop-weight",
+			"100000000",
+			"snap",
+			"-s",
+			"state.snap",
+		]);
+		let mut ext = TestExternalities::default();
+		ext.insert(b"version".to_vec(), vec![2]);
+		ext.commit_all().unwrap();
+		// an upgrade that changes nothing on the migrated state, but iterates over all items
+		// before it finds out.
+		let changes = OverlayedChanges::default();
+		let state = State::Snap { snapshot_path: vec!["state.snap".into()] };
+		let check = |weight| {
+			check_changes(&command, &state, &ext, &changes, weight, DbOpStats::default(), None)
+		};
+
+		let err = check(Weight::from_ref_time(2_000_000_000)).unwrap_err();
+		assert!(
+			err.to_string().contains("already migrated state consumed 2000000000 ps"),
+			"{}",
+			err
+		);
+		assert!(check(Weight::from_ref_time(25_000_000)).is_ok());
+	}
+
+	#[test]
+	fn state_root_mismatch_is_reported() {
+		let mut storage = Storage::default();
+		storage.top.insert(b"key".to_vec(), b"value".to_vec());
+		let ext = TestExternalities::new(storage);
+		let root = ext.backend.root().as_ref().to_vec();
+		assert!(ensure_state_root(&ext, &root).is_ok());
+
+		// the node claims a root of a state with more keys than were fetched.
+		let mut claimed = Storage::default();
+		claimed.top.insert(b"key".to_vec(), b"value".to_vec());
+		claimed.top.insert(b"missing".to_vec(), b"value".to_vec());
+		let claimed_root = TestExternalities::new(claimed).backend.root().as_ref().to_vec();
+		let err = ensure_state_root(&ext, &claimed_root).unwrap_err();
+		assert!(err.to_string().contains("but the node reports"));
+	}
+
+	#[test]
+	fn snapshots_are_split_into_states() {
+		let state = State::Snap { snapshot_path: vec!["a.snap".into(), "b.snap".into()] };
+		let states = state.per_snapshot();
+		assert_eq!(states.len(), 2);
+		assert!(
+			matches!(&states[1], State::Snap { snapshot_path } if snapshot_path == &[PathBuf::from("b.snap")])
+		);
+	}
+
+	#[test]
+	fn each_snapshot_is_upgraded_in_order() {
+		let dir = tempfile::tempdir().unwrap();
+		let a = dir.path().join("a.snap");
+		std::fs::write(&a, [7]).unwrap();
+		let missing = dir.path().join("missing.snap");
+		let b = dir.path().join("b.snap");
+		std::fs::write(&b, [9]).unwrap();
+		let state = State::Snap { snapshot_path: vec![a, missing.clone(), b] };
+		let states = state.per_snapshot();
+
+		// an upgrade whose weight is the content of the snapshot.
+		let upgrade = |index, state: &State| {
+			let path = match state {
+				State::Snap { snapshot_path } => snapshot_path[0].clone(),
+				_ => unreachable!(),
+			};
+			async move {
+				let content = std::fs::read(&path).map_err(|e| format!("{}", e))?;
+				Ok::<_, sc_cli::Error>(UpgradeSummary {
+					weight: Weight::from_parts(content[0] as u64, index as u64),
+					total_weight: Weight::zero(),
+					overlay_size: 0,
+				})
+			}
+		};
+		let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+		let results = runtime.block_on(upgrade_each(&states, upgrade));
+
+		assert_eq!(results.len(), 3);
+		let weights = results
+			.iter()
+			.map(|(_, result)| result.as_ref().ok().map(|summary| summary.weight))
+			.collect::<Vec<_>>();
+		assert_eq!(
+			weights,
+			vec![Some(Weight::from_parts(7, 0)), None, Some(Weight::from_parts(9, 2))],
+		);
+		let in_order = results.iter().zip(&states).all(|((state, _), expected)| {
+			// one result per snapshot, in the order of the snapshots.
+			std::ptr::eq(*state, expected)
+		});
+		assert!(in_order);
+
+		let err = ensure_all_upgraded(&results).unwrap_err().to_string();
+		assert!(err.starts_with("upgrade failed against 1 of 3 states"), "{}", err);
+		assert!(err.contains(&format!("{:?}", missing)), "{}", err);
+		assert!(!err.contains("b.snap"), "{}", err);
+	}
+
+	#[test]
+	fn raw_result_is_written_as_hex() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("raw-result");
+		write_raw_result(path.to_str().unwrap(), &[0x01, 0xab, 0xff]).unwrap();
+		assert_eq!(std::fs::read_to_string(&path).unwrap(), "0x01abff");
+	}
+
+	#[test]
+	fn removed_keys_only_reports_existing_keys() {
+		let mut ext = TestExternalities::default();
+		ext.insert(b"removed".to_vec(), b"value".to_vec());
+		ext.insert(b"changed".to_vec(), b"old".to_vec());
+		ext.commit_all().unwrap();
+
+		let mut changes = OverlayedChanges::default();
+		changes.set_storage(b"removed".to_vec(), None);
+		changes.set_storage(b"changed".to_vec(), Some(b"new".to_vec()));
+		changes.set_storage(b"never_existed".to_vec(), None);
+
+		assert_eq!(removed_keys(&ext, &changes).unwrap(), vec![b"removed".to_vec()]);
+	}
+
+	#[test]
+	fn removals_under_allowed_prefixes_pass() {
+		use clap::Parser;
+		let command = OnRuntimeUpgradeCmd::parse_from([
+			"on-runtime-upgrade",
+			"--forbid-key-removal",
+			"--allow-removal-prefix",
+			"0x0102",
+			"snap",
+			"-s",
+			"state.snap",
+		]);
+
+		let mut ext = TestExternalities::default();
+		ext.insert(vec![1, 2, 3], b"deprecated".to_vec());
+		ext.insert(vec![1, 3], b"kept".to_vec());
+		ext.commit_all().unwrap();
+
+		// a migration that cleans up a deprecated item.
+		let mut changes = OverlayedChanges::default();
+		changes.set_storage(vec![1, 2, 3], None);
+		assert!(ensure_no_removals(&ext, &changes, &command.allow_removal_prefix).is_ok());
+
+		changes.set_storage(vec![1, 3], None);
+		let err = ensure_no_removals(&ext, &changes, &command.allow_removal_prefix).unwrap_err();
+		assert!(err.to_string().contains("removed 1 storage keys"), "{}", err);
+		assert!(err.to_string().contains("0x0103"), "{}", err);
+	}
+
+	#[test]
+	fn pre_and_post_calls_are_made_in_order() {
+		use clap::Parser;
+		let command = OnRuntimeUpgradeCmd::parse_from([
+			"on-runtime-upgrade",
+			"--pre-call",
+			"MyApi_before",
+			"--post-call",
+			"MyApi_after",
+			"--post-call",
+			"MyApi_failing",
+			"--pre-call",
+			"MyApi_also_before",
+			"snap",
+			"-s",
+			"state.snap",
+		]);
+
+		let mut called = Vec::new();
+		let results = forward_runtime_api_calls(&command.pre_call, |method, _| {
+			called.push(method.to_string());
+			Ok(method.as_bytes().to_vec())
+		})
+		.unwrap();
+		assert_eq!(called, ["MyApi_before", "MyApi_also_before"]);
+		assert_eq!(results, [b"MyApi_before".to_vec(), b"MyApi_also_before".to_vec()]);
+
+		// the first failing call stops the rest and fails the command.
+		let mut called = Vec::new();
+		let err = forward_runtime_api_calls(&command.post_call, |method, _| {
+			called.push(method.to_string());
+			Err(format!("{} failed", method).into())
+		})
+		.unwrap_err();
+		assert_eq!(called, ["MyApi_after"]);
+		assert!(err.to_string().contains("MyApi_after failed"), "{}", err);
+	}
+
+	#[test]
+	fn runtime_call_arguments_are_forwarded() {
+		use clap::Parser;
+		let command = OnRuntimeUpgradeCmd::parse_from([
+			"on-runtime-upgrade",
+			"--pre-call",
+			"MyApi_check_account:0x0101",
+			"--pre-call",
+			"MyApi_check_invariant",
+			"snap",
+			"-s",
+			"state.snap",
+		]);
+
+		let mut forwarded = Vec::new();
+		let results = forward_runtime_api_calls(&command.pre_call, |method, args| {
+			forwarded.push((method.to_string(), args.to_vec()));
+			Ok(args.to_vec())
+		})
+		.unwrap();
+		assert_eq!(
+			forwarded,
+			[
+				("MyApi_check_account".to_string(), vec![1, 1]),
+				("MyApi_check_invariant".to_string(), vec![]),
+			],
+		);
+		assert_eq!(results, [vec![1, 1], vec![]]);
+
+		assert!(OnRuntimeUpgradeCmd::try_parse_from([
+			"on-runtime-upgrade",
+			"--pre-call",
+			"MyApi_check_account:0xzz",
+			"snap",
+			"-s",
+			"state.snap",
+		])
+		.is_err());
+	}
+
+	#[test]
+	fn requested_extensions_are_provided() {
+		use clap::Parser;
+		use sp_core::{crypto::KeyTypeId, offchain::StorageKind};
+
+		let parse = |flags: &[&str]| {
+			let args = ["on-runtime-upgrade"].iter().chain(flags).chain(&["snap", "-s", "a.snap"]);
+			OnRuntimeUpgradeCmd::parse_from(args)
+		};
+
+		let mut ext = TestExternalities::default();
+		ext.extensions.extend(upgrade_extensions(&parse(&["--with-offchain", "--with-keystore"])));
+		// a migration using offchain storage and the keystore.
+		ext.execute_with(|| {
+			sp_io::offchain::local_storage_set(StorageKind::PERSISTENT, b"key", b"value");
+			assert_eq!(
+				sp_io::offchain::local_storage_get(StorageKind::PERSISTENT, b"key"),
+				Some(b"value".to_vec()),
+			);
+			sp_io::crypto::sr25519_generate(KeyTypeId(*b"test"), None);
+		});
+
+		let mut extensions = upgrade_extensions(&parse(&[]));
+		assert!(extensions.get_mut(std::any::TypeId::of::<OffchainDbExt>()).is_none());
+		assert!(extensions.get_mut(std::any::TypeId::of::<KeystoreExt>()).is_none());
+	}
+
+	#[test]
+	fn frozen_time_makes_time_dependent_weight_reproducible() {
+		use clap::Parser;
+
+		let command = OnRuntimeUpgradeCmd::parse_from([
+			"on-runtime-upgrade",
+			"--freeze-time",
+			"1670000060000",
+			"snap",
+			"-s",
+			"a.snap",
+		]);
+		// a migration whose weight grows with the time elapsed since a stored timestamp.
+		let run = || {
+			let mut ext = TestExternalities::default();
+			ext.extensions.extend(upgrade_extensions(&command));
+			ext.execute_with(|| {
+				let elapsed = sp_io::offchain::timestamp().unix_millis() - 1670000000000;
+				Weight::from_parts(elapsed * 1_000, 0)
+			})
+		};
+
+		let weight = run();
+		assert_eq!(weight, Weight::from_parts(60_000_000, 0));
+		assert_eq!(run(), weight);
+	}
+
+	#[test]
+	fn version_changes_are_listed() {
+		let version_key = |pallet: &str| {
+			[twox_128(pallet.as_bytes()), twox_128(STORAGE_VERSION_STORAGE_KEY_POSTFIX)].concat()
+		};
+		let mut ext = TestExternalities::default();
+		ext.insert(version_key("Balances"), 1u16.encode());
+		ext.insert(version_key("Staking"), 9u16.encode());
+		ext.insert(version_key("Session"), 2u16.encode());
+		ext.commit_all().unwrap();
+
+		let mut changes = OverlayedChanges::default();
+		changes.set_storage(version_key("Balances"), Some(2u16.encode()));
+		changes.set_storage(version_key("Staking"), Some(11u16.encode()));
+		changes.set_storage(version_key("Session"), Some(2u16.encode()));
+		changes.set_storage(b"unrelated".to_vec(), Some(vec![1]));
+
+		let mut version_changes = version_changes(&ext, &changes).unwrap();
+		version_changes.sort_by_key(|change| change.old);
+		assert_eq!(
+			version_changes,
+			vec![
+				VersionChange {
+					pallet_prefix: twox_128(b"Balances").to_vec(),
+					old: Some(1),
+					new: Some(2),
+				},
+				VersionChange {
+					pallet_prefix: twox_128(b"Staking").to_vec(),
+					old: Some(9),
+					new: Some(11),
+				},
+			],
+		);
+
+		let table = version_changes_table(&version_changes, &["Balances".into()]);
+		assert!(table.contains("Balances"));
+		assert!(table.contains("1 -> 2"));
+		assert!(table.contains(&format!("0x{}", HexDisplay::from(&twox_128(b"Staking")))));
+		assert!(table.contains("9 -> 11"));
+	}
+
+	#[test]
+	fn results_are_posted_to_the_webhook() {
+		use std::io::{BufRead, BufReader, Read, Write};
+
+		let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let url = format!("http://{}/results", listener.local_addr().unwrap());
+		let server = std::thread::spawn(move || {
+			let (stream, _) = listener.accept().unwrap();
+			let mut reader = BufReader::new(stream.try_clone().unwrap());
+			let mut content_length = 0;
+			loop {
+				let mut line = String::new();
+				reader.read_line(&mut line).unwrap();
+				if line == "\r\n" {
+					break
+				}
+				if let Some(length) = line.to_lowercase().strip_prefix("content-length:") {
+					content_length = length.trim().parse().unwrap();
+				}
+			}
+			let mut body = vec![0; content_length];
+			reader.read_exact(&mut body).unwrap();
+			write!(&stream, "HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+				.unwrap();
+			serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+		});
+
+		let ok = State::Snap { snapshot_path: vec!["a.snap".into()] };
+		let failed = State::Snap { snapshot_path: vec!["b.snap".into()] };
+		let summary = UpgradeSummary {
+			weight: Weight::from_parts(10, 1),
+			total_weight: Weight::from_parts(100, 10),
+			overlay_size: 42,
+		};
+		let results: [(&State, sc_cli::Result<UpgradeSummary>); 2] =
+			[(&ok, Ok(summary)), (&failed, Err("boom".into()))];
+		let body = results_json(&results).to_string();
+		let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+		runtime.block_on(webhook::post_json(&url, &body, 0, Duration::from_millis(1))).unwrap();
+
+		let posted = server.join().unwrap();
+		assert_eq!(posted["failed"], 1);
+		assert_eq!(posted["states"][0]["ok"], true);
+		assert_eq!(posted["states"][0]["weight"]["ref_time"], 10);
+		assert_eq!(posted["states"][0]["total_weight"]["proof_size"], 10);
+		assert_eq!(posted["states"][0]["overlay_size"], 42);
+		assert_eq!(posted["states"][1]["ok"], false);
+		assert!(posted["states"][1]["error"].as_str().unwrap().contains("boom"));
+	}
+
+	#[test]
+	fn ci_summary_is_stable() {
+		let a = State::Snap { snapshot_path: vec!["a.snap".into()] };
+		let b = State::Snap { snapshot_path: vec!["b.snap".into()] };
+		let summary = |weight| UpgradeSummary {
+			weight,
+			total_weight: Weight::from_parts(100, 10),
+			overlay_size: 42,
+		};
+
+		let passing: [(&State, sc_cli::Result<UpgradeSummary>); 2] = [
+			(&a, Ok(summary(Weight::from_parts(10, 1)))),
+			(&b, Ok(summary(Weight::from_parts(5, 3)))),
+		];
+		assert_eq!(
+			ci_summary(&passing),
+			"TRY_RUNTIME_RESULT status=ok ref_time=10 proof_size=3 checks=pass",
+		);
+
+		let failing: [(&State, sc_cli::Result<UpgradeSummary>); 2] =
+			[(&a, Ok(summary(Weight::from_parts(10, 1)))), (&b, Err("check failed".into()))];
+		assert_eq!(
+			ci_summary(&failing),
+			"TRY_RUNTIME_RESULT status=fail ref_time=10 proof_size=1 checks=fail",
+		);
+	}
+
+	#[test]
+	fn upgrades_are_chained() {
+		let dir = tempfile::tempdir().unwrap();
+		let runtimes = [dir.path().join("v2.wasm"), dir.path().join("v3.wasm")];
+		std::fs::write(&runtimes[0], b"v2").unwrap();
+		std::fs::write(&runtimes[1], b"v3").unwrap();
+
+		let mut ext = TestExternalities::default();
+		ext.insert(b"version".to_vec(), b"v1".to_vec());
+		ext.commit_all().unwrap();
+
+		// a stub runtime that records its upgrade, on top of the previous ones.
+		let weights = chain_upgrades(
+			&mut ext,
+			&runtimes,
+			&StorageData(b"v4".to_vec()),
+			StateVersion::V1,
+			|ext| {
+				let code = ext.backend.storage(well_known_keys::CODE).unwrap().unwrap();
+				let version = ext.backend.storage(b"version").unwrap().unwrap();
+				let mut changes = OverlayedChanges::default();
+				changes.set_storage(b"version".to_vec(), Some([version, code.clone()].concat()));
+				Ok((changes, Weight::from_parts(code.len() as u64 * 10, 1)))
+			},
+		)
+		.unwrap();
+
+		assert_eq!(weights, vec![Weight::from_parts(20, 1); 2]);
+		assert_eq!(ext.backend.storage(b"version").unwrap(), Some(b"v1v2v3".to_vec()));
+		assert_eq!(ext.backend.storage(well_known_keys::CODE).unwrap(), Some(b"v4".to_vec()));
+	}
+
+	#[test]
+	fn under_declared_weight_is_reported() {
+		let declared = Weight::from_parts(1_000, 100);
+		assert_eq!(under_declaration(Weight::from_parts(1_000, 100), declared), None);
+		assert_eq!(under_declaration(Weight::from_parts(10, 10), declared), None);
+
+		let warning = under_declaration(Weight::from_parts(2_000, 100), declared).unwrap();
+		assert!(warning.starts_with("UNDER-DECLARED WEIGHT"));
+		assert!(under_declaration(Weight::from_parts(10, 101), declared).is_some());
+	}
+
+	#[test]
+	fn remote_runtime_is_executed_unless_a_local_one_is_given() {
+		let executed_code = |ext: &TestExternalities| {
+			BackendRuntimeCode::new(&ext.backend).fetch_runtime_code().unwrap().into_owned()
+		};
+		// the state as fetched from a node with this runtime on chain.
+		let mut storage = Storage::default();
+		storage.top.insert(well_known_keys::CODE.to_vec(), b"remote runtime".to_vec());
+
+		let mut ext = TestExternalities::new(storage.clone());
+		inject_keys(&mut ext, vec![]).unwrap();
+		let code = target_code(&ext, None).unwrap();
+		assert_eq!(code.1 .0, b"remote runtime".to_vec());
+		assert_eq!(executed_code(&ext), b"remote runtime".to_vec());
+
+		let local_code =
+			(StorageKey(well_known_keys::CODE.to_vec()), StorageData(b"local runtime".to_vec()));
+		let mut ext = TestExternalities::new(storage);
+		inject_keys(&mut ext, vec![local_code.clone()]).unwrap();
+		assert_eq!(target_code(&ext, Some(local_code)).unwrap().1 .0, b"local runtime".to_vec());
+		assert_eq!(executed_code(&ext), b"local runtime".to_vec());
+	}
+
+	#[test]
+	fn validation_errors_are_decoded() {
+		let method = "MyRuntimeApi_validate_state";
+		assert!(ensure_valid(method, &Ok::<(), Vec<u8>>(()).encode()).is_ok());
+
+		let err = ensure_valid(method, &Err::<(), Vec<u8>>(b"bad".to_vec()).encode()).unwrap_err();
+		assert!(err.to_string().ends_with("rejected the state after the upgrade: bad"), "{}", err);
+
+		let err = ensure_valid(method, &Err::<(), Vec<u8>>(vec![0xff, 0x01]).encode()).unwrap_err();
+		assert!(err.to_string().ends_with(": 0xff01"), "{}", err);
+		assert!(ensure_valid(method, &[2]).is_err());
+	}
+
+	#[test]
+	fn weight_deltas_are_signed_per_dimension() {
+		let declared = Weight::from_parts(1_000, 100);
+		let table = weight_delta_table(Weight::from_parts(400, 150), declared);
+		let rows = table
+			.lines()
+			.map(|line| line.split_whitespace().collect::<Vec<_>>())
+			.collect::<Vec<_>>();
+		assert_eq!(rows[0], ["dimension", "declared", "consumed", "delta"]);
+		assert_eq!(rows[1], ["ref_time", "1000", "400", "-600"]);
+		assert_eq!(rows[2], ["proof_size", "100", "150", "+50"]);
+	}
+
+	#[test]
+	fn min_keys_are_enforced() {
+		let mut storage = Storage::default();
+		for i in 0..3u8 {
+			storage.top.insert(vec![1, 1, i], vec![i]);
+		}
+		storage.top.insert(vec![2, 2], vec![]);
+		let ext = TestExternalities::new(storage);
+
+		assert!(ensure_min_keys(&ext, &[(vec![1, 1], 3), (vec![2], 1)]).is_ok());
+		let err = ensure_min_keys(&ext, &[(vec![1, 1], 4), (vec![2], 1)]).unwrap_err();
+		assert!(err.to_string().contains("0x0101: 3 keys, expected 4"));
+	}
+
+	#[test]
+	fn new_events_are_extracted() {
+		let before = vec![1u32].encode();
+		let after = vec![1u32, 2, 3].encode();
+		assert_eq!(
+			new_events(Some(&before[..]), Some(&after[..])).unwrap(),
+			(2, [2u32.encode(), 3u32.encode()].concat()),
+		);
+		assert_eq!(new_events(None, Some(&before[..])).unwrap(), (1, 1u32.encode()));
+		assert_eq!(new_events(Some(&before[..]), Some(&before[..])).unwrap(), (0, vec![]));
+		assert!(new_events(Some(&after[..]), Some(&before[..])).is_err());
+	}
+
+	#[test]
+	fn events_are_written_per_record() {
+		let record = EventRecord {
+			phase: "ApplyExtrinsic(1)".into(),
+			pallet: (5, "Balances".into()),
+			variant: (2, "Transfer".into()),
+			encoded: vec![0, 1, 0, 0, 0, 5, 2, 0],
+		};
+		let events = [record.encoded.clone(), record.encoded.clone()].concat();
+
+		assert_eq!(
+			events_json(2, &events, Some(&[record.clone(), record])),
+			serde_json::json!({
+				"count": 2,
+				"events": [
+					{
+						"phase": "ApplyExtrinsic(1)",
+						"pallet_index": 5,
+						"pallet": "Balances",
+						"event_index": 2,
+						"event": "Transfer",
+						"encoded": "0x0001000000050200",
+					},
+					{
+						"phase": "ApplyExtrinsic(1)",
+						"pallet_index": 5,
+						"pallet": "Balances",
+						"event_index": 2,
+						"event": "Transfer",
+						"encoded": "0x0001000000050200",
+					},
+				],
+			}),
+		);
+		assert_eq!(
+			events_json(2, &events, None),
+			serde_json::json!({
+				"count": 2,
+				"events": null,
+				"encoded": "0x00010000000502000001000000050200",
+			}),
+		);
+	}
+
+	#[test]
+	fn block_context_is_mocked() {
+		use clap::Parser;
+		use sp_runtime::testing::{Block as RawBlock, ExtrinsicWrapper, H256};
+		type Block = RawBlock<ExtrinsicWrapper<H256>>;
+
+		let command = OnRuntimeUpgradeCmd::parse_from([
+			"on-runtime-upgrade",
+			"--mock-timestamp",
+			"1670000000000",
+			"--mock-author",
+			"0x0101",
+			"--at-block-number",
+			"20000000",
+			"snap",
+			"-s",
+			"state.snap",
+		]);
+		let mut storage = Storage::default();
+		for (key, value) in mocked_block_context::<Block>(&command) {
+			storage.top.insert(key.0, value.0);
+		}
+
+		TestExternalities::new(storage).execute_with(|| {
+			let now = sp_io::storage::get(&[twox_128(b"Timestamp"), twox_128(b"Now")].concat());
+			assert_eq!(now.map(|v| u64::decode(&mut &v[..]).unwrap()), Some(1670000000000));
+			let author =
+				sp_io::storage::get(&[twox_128(b"Authorship"), twox_128(b"Author")].concat());
+			assert_eq!(author.as_deref(), Some(&[1u8, 1][..]));
+			let number = sp_io::storage::get(&[twox_128(b"System"), twox_128(b"Number")].concat());
+			assert_eq!(number.map(|v| u64::decode(&mut &v[..]).unwrap()), Some(20000000));
+		});
+	}
+
+	#[test]
+	fn runtime_change_during_the_fetch_is_acted_on() {
+		use jsonrpsee::{core::Error as RpcError, ws_server::WsServerBuilder, RpcModule};
+		use sp_core::H256;
+		use sp_runtime::{generic, traits::BlakeTwo256, OpaqueExtrinsic};
+		use sp_version::RuntimeVersion;
+		use std::sync::atomic::{AtomicU32, Ordering};
+		type Block = generic::Block<generic::Header<u32, BlakeTwo256>, OpaqueExtrinsic>;
+
+		let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+		runtime.block_on(async {
+			// a node that only serves the runtime version of the pinned block.
+			let pinned = H256::repeat_byte(1);
+			let version = Arc::new(AtomicU32::new(1));
+			let mut module = RpcModule::new(version.clone());
+			module
+				.register_method("state_getRuntimeVersion", move |params, version| {
+					if params.one::<Option<H256>>()? != Some(pinned) {
+						return Err(RpcError::Custom("only the pinned block is known".into()))
+					}
+					Ok(RuntimeVersion {
+						spec_name: "node".into(),
+						spec_version: version.load(Ordering::SeqCst),
+						..Default::default()
+					})
+				})
+				.unwrap();
+			let server = WsServerBuilder::default().build("127.0.0.1:0").await.unwrap();
+			let uri = &format!("ws://{}", server.local_addr().unwrap());
+			let handle = server.start(module).unwrap();
+
+			let version = &*version;
+			let fetches = &AtomicU32::new(0);
+			let spec =
+				move || async move { remote_spec::<Block>(uri, Some(pinned)).await.map(Some) };
+			// the runtime is upgraded during the first fetch of each run.
+			let fetch = move || async move {
+				if fetches.fetch_add(1, Ordering::SeqCst) == 0 {
+					version.fetch_add(1, Ordering::SeqCst);
+				}
+				Ok::<_, sc_cli::Error>(version.load(Ordering::SeqCst))
+			};
+
+			assert!(remote_spec::<Block>(uri, None).await.is_err());
+
+			assert_eq!(fetch_unchanged(OnVersionChange::Warn, 3, spec, fetch).await.unwrap(), 2);
+			assert_eq!(fetches.swap(0, Ordering::SeqCst), 1);
+
+			let err = fetch_unchanged(OnVersionChange::Fail, 3, spec, fetch).await.unwrap_err();
+			assert!(err.to_string().contains(r#"from ("node", 2) to ("node", 3)"#), "{}", err);
+			assert_eq!(fetches.swap(0, Ordering::SeqCst), 1);
+
+			assert_eq!(fetch_unchanged(OnVersionChange::Refetch, 3, spec, fetch).await.unwrap(), 4);
+			assert_eq!(fetches.swap(0, Ordering::SeqCst), 2);
+
+			// a runtime that is upgraded during every fetch.
+			let fetch = move || async move {
+				fetches.fetch_add(1, Ordering::SeqCst);
+				Ok::<_, sc_cli::Error>(version.fetch_add(1, Ordering::SeqCst))
+			};
+			let err = fetch_unchanged(OnVersionChange::Refetch, 2, spec, fetch).await.unwrap_err();
+			assert!(err.to_string().contains("giving up after 2 refetches"), "{}", err);
+			assert_eq!(fetches.swap(0, Ordering::SeqCst), 3);
+
+			handle.stop().unwrap();
+		});
+	}
+
+	#[test]
+	fn time_budget_aborts_a_slow_upgrade_promptly() {
+		let started = Instant::now();
+		let runtime = tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap();
+		// a migration that runs far longer than its budget.
+		let slow = within_time_budget(Duration::from_millis(50), || {
+			std::thread::sleep(Duration::from_secs(600));
+		});
+		let err = runtime.block_on(slow).unwrap_err();
+		assert!(err.to_string().starts_with("timeout: the upgrade did not finish"), "{}", err);
+		// the abandoned upgrade does not keep the runtime from shutting down.
+		drop(runtime);
+		assert!(started.elapsed() < Duration::from_secs(60), "took {:?}", started.elapsed());
+
+		let runtime = tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap();
+		let fast = within_time_budget(Duration::from_secs(600), || 42);
+		assert_eq!(runtime.block_on(fast).unwrap(), 42);
+	}
+
+	#[test]
+	fn heap_peak_covers_the_allocations_of_the_upgrade() {
+		let allocated = 3 * 1024 * 1024;
+		let heap_peak = measure_heap_peak(|| {
+			let mut memory = vec![0u8; 8 * 1024 * 1024];
+			let mut allocator = sc_allocator::FreeingBumpHeapAllocator::new(0);
+			let ptr = allocator.allocate(&mut memory[..], allocated).unwrap();
+			allocator.deallocate(&mut memory[..], ptr).unwrap();
+			Ok((Ok(Vec::new()), Some(allocator.stats())))
+		})
+		.unwrap();
+		assert!(heap_peak >= allocated, "{} < {}", heap_peak, allocated);
+
+		assert!(measure_heap_peak(|| Ok((Ok(Vec::new()), None))).is_err());
+	}
+}
@@ -0,0 +1,279 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2022 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Counting of the database operations performed by a runtime call.
+
+use std::{
+	cell::{Cell, RefCell},
+	collections::BTreeSet,
+};
+
+use sp_core::{
+	storage::{ChildInfo, StateVersion},
+	Hasher,
+};
+use sp_state_machine::{
+	Backend, OverlayedChanges, StateMachineStats, StorageKey, StorageValue, UsageInfo,
+};
+
+/// The database operations performed by a runtime call.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct DbOpStats {
+	/// The number of reads that reached the backend, i.e. were not served by the overlay.
+	pub reads: u64,
+	/// The number of distinct keys among `reads`.
+	pub unique_reads: u64,
+	/// The number of distinct keys written or removed, i.e. the writes that reach the database.
+	pub writes: u64,
+}
+
+/// A [`Backend`] that forwards to `inner` and counts the storage reads that reach it.
+///
+/// Child trie reads are counted separately from top trie reads of the same key.
+#[derive(Debug)]
+pub(crate) struct CountingBackend<'a, B> {
+	inner: &'a B,
+	reads: Cell<u64>,
+	read_keys: RefCell<BTreeSet<(Option<Vec<u8>>, Vec<u8>)>>,
+	max_reads: Option<u64>,
+}
+
+impl<'a, B> CountingBackend<'a, B> {
+	pub fn new(inner: &'a B) -> Self {
+		Self { inner, reads: Cell::new(0), read_keys: Default::default(), max_reads: None }
+	}
+
+	/// Panic on the read after the first `max_reads` reads, if set.
+	///
+	/// The externalities can not fail a read, so the panic is what aborts the runtime call. The
+	/// executor reports it as an error of the call, see [`Self::exceeded_budget`].
+	pub fn with_read_budget(mut self, max_reads: Option<u64>) -> Self {
+		self.max_reads = max_reads;
+		self
+	}
+
+	/// The read budget, if it was exceeded.
+	pub fn exceeded_budget(&self) -> Option<u64> {
+		self.max_reads.filter(|max_reads| self.reads.get() > *max_reads)
+	}
+
+	/// The reads counted so far, and the writes held in `changes`.
+	pub fn stats(&self, changes: &OverlayedChanges) -> DbOpStats {
+		let top = changes.changes().count();
+		let children = changes
+			.children()
+			.map(|(child_changes, _)| child_changes.count())
+			.sum::<usize>();
+		DbOpStats {
+			reads: self.reads.get(),
+			unique_reads: self.read_keys.borrow().len() as u64,
+			writes: (top + children) as u64,
+		}
+	}
+
+	fn note_read(&self, child_info: Option<&ChildInfo>, key: &[u8]) {
+		self.reads.set(self.reads.get() + 1);
+		self.read_keys
+			.borrow_mut()
+			.insert((child_info.map(|c| c.storage_key().to_vec()), key.to_vec()));
+		if let Some(max_reads) = self.exceeded_budget() {
+			panic!("exceeded the budget of {} storage reads", max_reads);
+		}
+	}
+}
+
+impl<'a, H: Hasher, B: Backend<H>> Backend<H> for CountingBackend<'a, B> {
+	type Error = B::Error;
+	type Transaction = B::Transaction;
+	type TrieBackendStorage = B::TrieBackendStorage;
+
+	fn storage(&self, key: &[u8]) -> Result<Option<StorageValue>, Self::Error> {
+		self.note_read(None, key);
+		self.inner.storage(key)
+	}
+
+	fn storage_hash(&self, key: &[u8]) -> Result<Option<H::Out>, Self::Error> {
+		self.note_read(None, key);
+		self.inner.storage_hash(key)
+	}
+
+	fn child_storage(
+		&self,
+		child_info: &ChildInfo,
+		key: &[u8],
+	) -> Result<Option<StorageValue>, Self::Error> {
+		self.note_read(Some(child_info), key);
+		self.inner.child_storage(child_info, key)
+	}
+
+	fn child_storage_hash(
+		&self,
+		child_info: &ChildInfo,
+		key: &[u8],
+	) -> Result<Option<H::Out>, Self::Error> {
+		self.note_read(Some(child_info), key);
+		self.inner.child_storage_hash(child_info, key)
+	}
+
+	fn next_storage_key(&self, key: &[u8]) -> Result<Option<StorageKey>, Self::Error> {
+		self.inner.next_storage_key(key)
+	}
+
+	fn next_child_storage_key(
+		&self,
+		child_info: &ChildInfo,
+		key: &[u8],
+	) -> Result<Option<StorageKey>, Self::Error> {
+		self.inner.next_child_storage_key(child_info, key)
+	}
+
+	fn apply_to_key_values_while<F: FnMut(Vec<u8>, Vec<u8>) -> bool>(
+		&self,
+		child_info: Option<&ChildInfo>,
+		prefix: Option<&[u8]>,
+		start_at: Option<&[u8]>,
+		f: F,
+		allow_missing: bool,
+	) -> Result<bool, Self::Error> {
+		self.inner
+			.apply_to_key_values_while(child_info, prefix, start_at, f, allow_missing)
+	}
+
+	fn apply_to_keys_while<F: FnMut(&[u8]) -> bool>(
+		&self,
+		child_info: Option<&ChildInfo>,
+		prefix: Option<&[u8]>,
+		start_at: Option<&[u8]>,
+		f: F,
+	) {
+		self.inner.apply_to_keys_while(child_info, prefix, start_at, f)
+	}
+
+	fn for_keys_with_prefix<F: FnMut(&[u8])>(&self, prefix: &[u8], f: F) {
+		self.inner.for_keys_with_prefix(prefix, f)
+	}
+
+	fn for_key_values_with_prefix<F: FnMut(&[u8], &[u8])>(&self, prefix: &[u8], f: F) {
+		self.inner.for_key_values_with_prefix(prefix, f)
+	}
+
+	fn for_child_keys_with_prefix<F: FnMut(&[u8])>(
+		&self,
+		child_info: &ChildInfo,
+		prefix: &[u8],
+		f: F,
+	) {
+		self.inner.for_child_keys_with_prefix(child_info, prefix, f)
+	}
+
+	fn storage_root<'b>(
+		&self,
+		delta: impl Iterator<Item = (&'b [u8], Option<&'b [u8]>)>,
+		state_version: StateVersion,
+	) -> (H::Out, Self::Transaction)
+	where
+		H::Out: Ord,
+	{
+		self.inner.storage_root(delta, state_version)
+	}
+
+	fn child_storage_root<'b>(
+		&self,
+		child_info: &ChildInfo,
+		delta: impl Iterator<Item = (&'b [u8], Option<&'b [u8]>)>,
+		state_version: StateVersion,
+	) -> (H::Out, bool, Self::Transaction)
+	where
+		H::Out: Ord,
+	{
+		self.inner.child_storage_root(child_info, delta, state_version)
+	}
+
+	fn pairs(&self) -> Vec<(StorageKey, StorageValue)> {
+		self.inner.pairs()
+	}
+
+	fn register_overlay_stats(&self, stats: &StateMachineStats) {
+		self.inner.register_overlay_stats(stats)
+	}
+
+	fn usage_info(&self) -> UsageInfo {
+		self.inner.usage_info()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use remote_externalities::TestExternalities;
+	use sp_core::{storage::Storage, Blake2Hasher};
+	use sp_externalities::Externalities;
+	use sp_state_machine::{Ext, StorageTransactionCache};
+
+	#[test]
+	fn reads_and_writes_are_counted() {
+		let mut storage = Storage::default();
+		storage.top.insert(b"a".to_vec(), b"1".to_vec());
+		storage.top.insert(b"b".to_vec(), b"2".to_vec());
+		let ext = TestExternalities::new(storage);
+
+		let backend = CountingBackend::new(&ext.backend);
+		let mut changes = OverlayedChanges::default();
+		let mut cache = StorageTransactionCache::<_, Blake2Hasher>::default();
+		{
+			let mut ext = Ext::new(&mut changes, &mut cache, &backend, None);
+			assert_eq!(ext.storage(b"a"), Some(b"1".to_vec()));
+			assert_eq!(ext.storage(b"a"), Some(b"1".to_vec()));
+			assert_eq!(ext.storage(b"b"), Some(b"2".to_vec()));
+			assert_eq!(ext.storage(b"c"), None);
+
+			ext.set_storage(b"a".to_vec(), b"3".to_vec());
+			ext.set_storage(b"a".to_vec(), b"4".to_vec());
+			ext.clear_storage(b"b");
+			// served by the overlay.
+			assert_eq!(ext.storage(b"a"), Some(b"4".to_vec()));
+		}
+
+		assert_eq!(backend.stats(&changes), DbOpStats { reads: 4, unique_reads: 3, writes: 2 });
+	}
+
+	#[test]
+	fn read_budget_is_enforced() {
+		let mut storage = Storage::default();
+		storage.top.insert(b"a".to_vec(), b"1".to_vec());
+		let ext = TestExternalities::new(storage);
+		let read_times = |backend: &CountingBackend<_>, times| {
+			let mut changes = OverlayedChanges::default();
+			let mut cache = StorageTransactionCache::<_, Blake2Hasher>::default();
+			let mut ext = Ext::new(&mut changes, &mut cache, backend, None);
+			for _ in 0..times {
+				assert_eq!(ext.storage(b"a"), Some(b"1".to_vec()));
+			}
+		};
+
+		let within = CountingBackend::new(&ext.backend).with_read_budget(Some(3));
+		read_times(&within, 3);
+		assert_eq!(within.exceeded_budget(), None);
+
+		let runaway = CountingBackend::new(&ext.backend).with_read_budget(Some(3));
+		let aborted =
+			std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| read_times(&runaway, 10)));
+		assert!(aborted.is_err());
+		assert_eq!(runaway.exceeded_budget(), Some(3));
+		assert_eq!(runaway.stats(&Default::default()).reads, 4);
+	}
+}
@@ -0,0 +1,345 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2022 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Splitting of encoded `System::Events` into records, with the type information of the runtime
+//! metadata.
+//!
+//! The SCALE encoding is not self-describing, so the length of an event record is only known
+//! from its type. Only the names of the phase, pallet and event variant of each record are
+//! decoded, its fields are left encoded.
+
+use frame_metadata::{RuntimeMetadata, RuntimeMetadataPrefixed, StorageEntryType};
+use parity_scale_codec::{Compact, Decode};
+use scale_info::{form::PortableForm, PortableRegistry, TypeDef, TypeDefPrimitive, Variant};
+
+/// One record of `System::Events`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct EventRecord {
+	/// The phase of the block the event was deposited in, e.g. `ApplyExtrinsic(1)`.
+	pub phase: String,
+	/// The index and name of the pallet that deposited the event.
+	pub pallet: (u8, String),
+	/// The index and name of the event variant.
+	pub variant: (u8, String),
+	/// The SCALE encoding of the whole record.
+	pub encoded: Vec<u8>,
+}
+
+/// Splits encoded `System::Events` into [`EventRecord`]s.
+pub(crate) struct EventDecoder {
+	types: PortableRegistry,
+	/// The type of an element of `System::Events`.
+	record: u32,
+}
+
+impl EventDecoder {
+	/// Create a decoder from the SCALE encoded metadata of a runtime.
+	///
+	/// Only V14 metadata is supported, as earlier versions lack the type information.
+	pub fn new(mut metadata: &[u8]) -> Result<Self, String> {
+		let metadata = RuntimeMetadataPrefixed::decode(&mut metadata)
+			.map_err(|e| format!("failed to decode the runtime metadata: {}", e))?;
+		let metadata = match metadata.1 {
+			RuntimeMetadata::V14(metadata) => metadata,
+			other => return Err(format!("unsupported metadata version {}", other.version())),
+		};
+
+		let events = metadata
+			.pallets
+			.iter()
+			.filter(|pallet| pallet.name == "System")
+			.flat_map(|pallet| pallet.storage.iter().flat_map(|storage| &storage.entries))
+			.find(|entry| entry.name == "Events")
+			.ok_or("the metadata has no System::Events")?;
+		let events = match &events.ty {
+			StorageEntryType::Plain(ty) => ty.id(),
+			_ => return Err("System::Events is not a plain storage value".into()),
+		};
+		let record = match metadata.types.resolve(events).map(|ty| ty.type_def()) {
+			Some(TypeDef::Sequence(sequence)) => sequence.type_param().id(),
+			_ => return Err("System::Events is not a sequence".into()),
+		};
+
+		Ok(Self { types: metadata.types, record })
+	}
+
+	/// Split the `count` records concatenated in `events`.
+	pub fn decode(&self, count: u32, mut events: &[u8]) -> Result<Vec<EventRecord>, String> {
+		let records = (0..count)
+			.map(|_| {
+				let start = events;
+				let record = self.describe(&mut events)?;
+				let encoded = start[..start.len() - events.len()].to_vec();
+				Ok(EventRecord { encoded, ..record })
+			})
+			.collect::<Result<Vec<_>, String>>()?;
+		if !events.is_empty() {
+			return Err(format!("{} bytes left after {} event records", events.len(), count))
+		}
+		Ok(records)
+	}
+
+	/// Decode the names of one record at the start of `input`, and skip it.
+	fn describe(&self, input: &mut &[u8]) -> Result<EventRecord, String> {
+		let fields = match self.type_def(self.record)? {
+			TypeDef::Composite(composite) => composite.fields(),
+			_ => return Err("an event record is not a struct".into()),
+		};
+
+		let mut record = EventRecord {
+			phase: String::new(),
+			pallet: (0, String::new()),
+			variant: (0, String::new()),
+			encoded: Vec::new(),
+		};
+		for field in fields {
+			let ty = field.ty().id();
+			let data: &[u8] = input;
+			match field.name().map(|name| name.as_str()) {
+				Some("phase") => {
+					let (variant, mut rest) = self.peek_variant(ty, data)?;
+					record.phase = variant.name().clone();
+					// `ApplyExtrinsic` carries the index of the extrinsic.
+					if let [index] = variant.fields() {
+						if let TypeDef::Primitive(TypeDefPrimitive::U32) =
+							self.type_def(index.ty().id())?
+						{
+							let index = u32::decode(&mut rest).map_err(|e| e.to_string())?;
+							record.phase = format!("{}({})", record.phase, index);
+						}
+					}
+				},
+				Some("event") => {
+					let (pallet, rest) = self.peek_variant(ty, data)?;
+					record.pallet = (pallet.index(), pallet.name().clone());
+					if let [inner] = pallet.fields() {
+						let (variant, _) = self.peek_variant(inner.ty().id(), rest)?;
+						record.variant = (variant.index(), variant.name().clone());
+					}
+				},
+				_ => (),
+			}
+			self.skip(ty, input)?;
+		}
+		Ok(record)
+	}
+
+	/// The variant of the enum of type `ty` at the start of `input`, and the input after its
+	/// index.
+	fn peek_variant<'a>(
+		&self,
+		ty: u32,
+		input: &'a [u8],
+	) -> Result<(&Variant<PortableForm>, &'a [u8]), String> {
+		let variants = match self.type_def(ty)? {
+			TypeDef::Variant(variant) => variant.variants(),
+			_ => return Err(format!("type {} is not an enum", ty)),
+		};
+		let (index, rest) = input.split_first().ok_or("unexpected end of the events")?;
+		let variant = variants
+			.iter()
+			.find(|variant| variant.index() == *index)
+			.ok_or_else(|| format!("type {} has no variant {}", ty, index))?;
+		Ok((variant, rest))
+	}
+
+	/// Advance `input` past one value of type `ty`.
+	fn skip(&self, ty: u32, input: &mut &[u8]) -> Result<(), String> {
+		match self.type_def(ty)? {
+			TypeDef::Composite(composite) =>
+				for field in composite.fields() {
+					self.skip(field.ty().id(), input)?;
+				},
+			TypeDef::Variant(_) => {
+				let (variant, rest) = self.peek_variant(ty, input)?;
+				*input = rest;
+				for field in variant.fields() {
+					self.skip(field.ty().id(), input)?;
+				}
+			},
+			TypeDef::Sequence(sequence) =>
+				for _ in 0..decode_len(input)? {
+					self.skip(sequence.type_param().id(), input)?;
+				},
+			TypeDef::Array(array) =>
+				for _ in 0..array.len() {
+					self.skip(array.type_param().id(), input)?;
+				},
+			TypeDef::Tuple(tuple) =>
+				for field in tuple.fields() {
+					self.skip(field.id(), input)?;
+				},
+			TypeDef::Primitive(primitive) => {
+				let len = match primitive {
+					TypeDefPrimitive::Bool | TypeDefPrimitive::U8 | TypeDefPrimitive::I8 => 1,
+					TypeDefPrimitive::U16 | TypeDefPrimitive::I16 => 2,
+					TypeDefPrimitive::Char | TypeDefPrimitive::U32 | TypeDefPrimitive::I32 => 4,
+					TypeDefPrimitive::U64 | TypeDefPrimitive::I64 => 8,
+					TypeDefPrimitive::U128 | TypeDefPrimitive::I128 => 16,
+					TypeDefPrimitive::U256 | TypeDefPrimitive::I256 => 32,
+					TypeDefPrimitive::Str => decode_len(input)?,
+				};
+				advance(input, len)?;
+			},
+			TypeDef::Compact(_) => {
+				// The length of a compact integer is in the two lowest bits of its first byte.
+				let first = *input.first().ok_or("unexpected end of the events")?;
+				let len = match first & 0b11 {
+					0b00 => 1,
+					0b01 => 2,
+					0b10 => 4,
+					_ => (first >> 2) as usize + 5,
+				};
+				advance(input, len)?;
+			},
+			TypeDef::BitSequence(bits) => {
+				let store = match self.type_def(bits.bit_store_type().id())? {
+					TypeDef::Primitive(TypeDefPrimitive::U8) => 1,
+					TypeDef::Primitive(TypeDefPrimitive::U16) => 2,
+					TypeDef::Primitive(TypeDefPrimitive::U32) => 4,
+					TypeDef::Primitive(TypeDefPrimitive::U64) => 8,
+					_ => return Err(format!("unsupported bit store of type {}", ty)),
+				};
+				let bits = decode_len(input)?;
+				advance(input, (bits + store * 8 - 1) / (store * 8) * store)?;
+			},
+		}
+		Ok(())
+	}
+
+	fn type_def(&self, ty: u32) -> Result<&TypeDef<PortableForm>, String> {
+		self.types
+			.resolve(ty)
+			.map(|ty| ty.type_def())
+			.ok_or_else(|| format!("type {} is not in the metadata", ty))
+	}
+}
+
+/// Decode the compact length prefix at the start of `input`.
+fn decode_len(input: &mut &[u8]) -> Result<usize, String> {
+	<Compact<u32>>::decode(input)
+		.map(|len| len.0 as usize)
+		.map_err(|e| format!("failed to decode a length: {}", e))
+}
+
+/// Advance `input` by `len` bytes.
+fn advance(input: &mut &[u8], len: usize) -> Result<(), String> {
+	if input.len() < len {
+		return Err("unexpected end of the events".into())
+	}
+	*input = &input[len..];
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use frame_metadata::{
+		ExtrinsicMetadata, PalletMetadata, PalletStorageMetadata, RuntimeMetadataV14,
+		StorageEntryMetadata, StorageEntryModifier,
+	};
+	use parity_scale_codec::Encode;
+	use scale_info::{meta_type, TypeInfo};
+
+	#[allow(dead_code)]
+	#[derive(TypeInfo)]
+	enum Phase {
+		ApplyExtrinsic(u32),
+		Finalization,
+		Initialization,
+	}
+
+	#[allow(dead_code)]
+	#[derive(TypeInfo)]
+	enum BalancesEvent {
+		Other(Vec<u8>),
+		#[codec(index = 2)]
+		Transfer { from: u64, to: u64, amount: u128 },
+	}
+
+	#[allow(dead_code)]
+	#[derive(TypeInfo)]
+	enum RuntimeEvent {
+		#[codec(index = 5)]
+		Balances(BalancesEvent),
+	}
+
+	#[allow(dead_code)]
+	#[derive(TypeInfo)]
+	struct Record {
+		phase: Phase,
+		event: RuntimeEvent,
+		topics: Vec<[u8; 32]>,
+	}
+
+	fn metadata() -> Vec<u8> {
+		let system = PalletMetadata {
+			name: "System",
+			storage: Some(PalletStorageMetadata {
+				prefix: "System",
+				entries: vec![StorageEntryMetadata {
+					name: "Events",
+					modifier: StorageEntryModifier::Default,
+					ty: StorageEntryType::Plain(meta_type::<Vec<Record>>()),
+					default: vec![0],
+					docs: vec![],
+				}],
+			}),
+			calls: None,
+			event: None,
+			constants: vec![],
+			error: None,
+			index: 0,
+		};
+		let extrinsic =
+			ExtrinsicMetadata { ty: meta_type::<()>(), version: 4, signed_extensions: vec![] };
+		let metadata = RuntimeMetadataV14::new(vec![system], extrinsic, meta_type::<()>());
+		RuntimeMetadataPrefixed::from(metadata).encode()
+	}
+
+	#[test]
+	fn records_are_split_and_named() {
+		let transfer = [vec![0], 1u32.encode(), vec![5, 2], (1u64, 2u64, 10u128).encode(), vec![0]]
+			.concat();
+		let other =
+			[vec![2], vec![5, 0], vec![1u8, 2, 3].encode(), vec![[7u8; 32]].encode()].concat();
+
+		let decoder = EventDecoder::new(&metadata()).unwrap();
+		let records = decoder.decode(2, &[&transfer[..], &other[..]].concat()).unwrap();
+
+		assert_eq!(
+			records,
+			vec![
+				EventRecord {
+					phase: "ApplyExtrinsic(1)".into(),
+					pallet: (5, "Balances".into()),
+					variant: (2, "Transfer".into()),
+					encoded: transfer.clone(),
+				},
+				EventRecord {
+					phase: "Initialization".into(),
+					pallet: (5, "Balances".into()),
+					variant: (0, "Other".into()),
+					encoded: other.clone(),
+				},
+			],
+		);
+
+		assert!(decoder.decode(2, &transfer).is_err());
+		assert!(decoder.decode(1, &[&transfer[..], &[0]].concat()).is_err());
+	}
+}
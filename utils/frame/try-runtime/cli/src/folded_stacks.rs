@@ -0,0 +1,114 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2022 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Recording of the time spent in the phases of a command, as folded stacks.
+//!
+//! Each line of the folded stack format is a stack of `;` separated frames, outermost first,
+//! followed by a space and the number of samples of that stack. Tools such as `inferno` and
+//! `flamegraph.pl` render it as a flamegraph. Here, a sample is a microsecond.
+
+use std::{path::Path, time::Instant};
+
+/// The time spent in each recorded stack, in the order the stacks were first recorded.
+#[derive(Debug, Default)]
+pub(crate) struct FoldedStacks {
+	stacks: Vec<(String, u128)>,
+}
+
+impl FoldedStacks {
+	/// Record the time since `started` as spent in `phase`, below the frames of `stack`.
+	///
+	/// The time of a stack recorded more than once is summed.
+	pub fn record(&mut self, stack: &[&str], phase: &str, started: Instant) {
+		let micros = started.elapsed().as_micros();
+		let folded = stack.iter().chain(&[phase]).copied().collect::<Vec<_>>().join(";");
+		self.add(folded, micros);
+	}
+
+	/// Add the stacks recorded in `other`, summing the time of the stacks recorded in both.
+	pub fn append(&mut self, other: FoldedStacks) {
+		for (folded, micros) in other.stacks {
+			self.add(folded, micros);
+		}
+	}
+
+	fn add(&mut self, folded: String, micros: u128) {
+		match self.stacks.iter_mut().find(|(recorded, _)| *recorded == folded) {
+			Some((_, total)) => *total += micros,
+			None => self.stacks.push((folded, micros)),
+		}
+	}
+
+	/// The recorded stacks in the folded stack format.
+	pub fn folded(&self) -> String {
+		self.stacks.iter().map(|(stack, micros)| format!("{} {}\n", stack, micros)).collect()
+	}
+
+	/// Write the recorded stacks to the file at `path`, in the folded stack format.
+	pub fn write(&self, path: &Path) -> std::io::Result<()> {
+		std::fs::write(path, self.folded())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn phases_are_written_as_folded_stacks() {
+		let mut stacks = FoldedStacks::default();
+		let started = Instant::now();
+		stacks.record(&["on_runtime_upgrade"], "fetch_state", started);
+		stacks.record(&["on_runtime_upgrade"], "execute_upgrade", started);
+		stacks.record(&["on_runtime_upgrade"], "fetch_state", started);
+		stacks.record(&["on_runtime_upgrade"], "checks", started);
+
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("stacks.folded");
+		stacks.write(&path).unwrap();
+		let written = std::fs::read_to_string(&path).unwrap();
+
+		let frames = written
+			.lines()
+			.map(|line| {
+				let (stack, micros) = line.rsplit_once(' ').unwrap();
+				micros.parse::<u128>().unwrap();
+				stack
+			})
+			.collect::<Vec<_>>();
+		assert_eq!(
+			frames,
+			[
+				"on_runtime_upgrade;fetch_state",
+				"on_runtime_upgrade;execute_upgrade",
+				"on_runtime_upgrade;checks",
+			],
+		);
+	}
+
+	#[test]
+	fn appended_stacks_are_summed() {
+		let mut stacks = FoldedStacks::default();
+		stacks.add("a;x".into(), 1);
+		let mut other = FoldedStacks::default();
+		other.add("a;x".into(), 2);
+		other.add("a;y".into(), 3);
+
+		stacks.append(other);
+		assert_eq!(stacks.folded(), "a;x 3\na;y 3\n");
+	}
+}
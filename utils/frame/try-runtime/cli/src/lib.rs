@@ -267,6 +267,10 @@
 
 #![cfg(feature = "try-runtime")]
 
+use crate::{
+	db_stats::{CountingBackend, DbOpStats},
+	node_access::NodeAccessRecorder,
+};
 use parity_scale_codec::Decode;
 use remote_externalities::{
 	Builder, Mode, OfflineConfig, OnlineConfig, SnapshotConfig, TestExternalities,
@@ -298,10 +302,19 @@ use sp_runtime::{
 use sp_state_machine::{OverlayedChanges, StateMachine, TrieBackendBuilder};
 use sp_version::StateVersion;
 use std::{fmt::Debug, path::PathBuf, str::FromStr};
-use substrate_rpc_client::{ws_client, StateApi};
+use substrate_rpc_client::{ws_client, ChainApi, StateApi};
 
 mod commands;
+pub(crate) mod db_stats;
+pub(crate) mod events;
+mod folded_stacks;
+mod memory;
+pub(crate) mod node_access;
 pub(crate) mod parse;
+mod webhook;
+
+pub use commands::follow_chain::MultiBlockSummary;
+
 pub(crate) const LOG_TARGET: &str = "try-runtime::cli";
 
 /// Possible commands of `try-runtime`.
@@ -431,6 +444,13 @@ pub struct SharedParams {
 	/// State version that is used by the chain.
 	#[arg(long, default_value_t = StateVersion::V1, value_parser = parse::state_version)]
 	pub state_version: StateVersion,
+
+	/// Exit with an error once the resident memory of the process exceeds this many MB.
+	///
+	/// Use this to fail cleanly instead of being killed by the OOM killer. Only supported on
+	/// Linux; ignored with a warning elsewhere.
+	#[arg(long, value_name = "MB")]
+	pub max_memory_mb: Option<u64>,
 }
 
 /// Our `try-runtime` command.
@@ -451,9 +471,12 @@ pub enum State {
 	/// Use a state snapshot as the source of runtime state.
 	///
 	/// This can be crated by passing a value to [`State::Live::snapshot_path`].
+	///
+	/// Only [`Command::OnRuntimeUpgrade`] accepts more than one snapshot, and runs against each of
+	/// them in turn.
 	Snap {
-		#[arg(short, long)]
-		snapshot_path: PathBuf,
+		#[arg(short, long, required = true, num_args = 1..)]
+		snapshot_path: Vec<PathBuf>,
 	},
 
 	/// Use a live chain as the source of runtime state.
@@ -469,7 +492,7 @@ pub enum State {
 		/// The block hash at which to fetch the state.
 		///
 		/// If non provided, then the latest finalized head is used. This is particularly useful
-		/// for [`Command::OnRuntimeUpgrade`].
+		/// for [`Command::OnRuntimeUpgrade`]. If `-`, the hash is read from the standard input.
 		#[arg(
 			short,
 			long,
@@ -493,6 +516,13 @@ pub enum State {
 		/// Otherwise, it must be enabled explicitly using this flag.
 		#[arg(long)]
 		child_tree: bool,
+
+		/// Do not fetch the child-keys at all, even if the entire state is scraped.
+		///
+		/// Fetching child trees is expensive on chains that use them a lot. Migrations that
+		/// touch child storage are not tested accurately with this flag.
+		#[arg(long, conflicts_with = "child_tree")]
+		skip_child_tries: bool,
 	},
 }
 
@@ -504,21 +534,30 @@ impl State {
 		<Block::Hash as FromStr>::Err: Debug,
 	{
 		Ok(match self {
-			State::Snap { snapshot_path } =>
-				Builder::<Block>::new().mode(Mode::Offline(OfflineConfig {
+			State::Snap { snapshot_path } => match &snapshot_path[..] {
+				[snapshot_path] => Builder::<Block>::new().mode(Mode::Offline(OfflineConfig {
 					state_snapshot: SnapshotConfig::new(snapshot_path),
 				})),
-			State::Live { snapshot_path, pallet, uri, at, child_tree } => {
+				_ => return Err("only a single snapshot can be used by this command".into()),
+			},
+			State::Live { snapshot_path, pallet, uri, at, child_tree, skip_child_tries } => {
 				let at = match at {
 					Some(at_str) => Some(hash_of::<Block>(at_str)?),
 					None => None,
 				};
+				if *skip_child_tries {
+					log::warn!(
+						target: LOG_TARGET,
+						"child trees are not fetched, migrations touching child storage are \
+						not tested accurately",
+					);
+				}
 				let mut builder = Builder::<Block>::new()
 					.mode(Mode::Online(OnlineConfig {
 						transport: uri.to_owned().into(),
 						state_snapshot: snapshot_path.as_ref().map(SnapshotConfig::new),
 						pallets: pallet.clone(),
-						scrape_children: true,
+						scrape_children: !skip_child_tries,
 						at,
 					}))
 					.inject_hashed_key(
@@ -532,6 +571,17 @@ impl State {
 		})
 	}
 
+	/// Split self into one state per snapshot, if self is `Snap`.
+	pub(crate) fn per_snapshot(&self) -> Vec<State> {
+		match self {
+			State::Snap { snapshot_path } => snapshot_path
+				.iter()
+				.map(|path| State::Snap { snapshot_path: vec![path.clone()] })
+				.collect(),
+			State::Live { .. } => vec![self.clone()],
+		}
+	}
+
 	/// Get the uri, if self is `Live`.
 	pub(crate) fn live_uri(&self) -> Option<String> {
 		match self {
@@ -552,6 +602,10 @@ impl TryRuntimeCmd {
 		<NumberFor<Block> as FromStr>::Err: Debug,
 		ExecDispatch: NativeExecutionDispatch + 'static,
 	{
+		if let Some(max_memory_mb) = self.shared.max_memory_mb {
+			memory::spawn_memory_watchdog(max_memory_mb);
+		}
+
 		match &self.command {
 			Command::OnRuntimeUpgrade(ref cmd) =>
 				commands::on_runtime_upgrade::on_runtime_upgrade::<Block, ExecDispatch>(
@@ -580,7 +634,8 @@ impl TryRuntimeCmd {
 					cmd.clone(),
 					config,
 				)
-				.await,
+				.await
+				.map(|_| ()),
 		}
 	}
 }
@@ -686,6 +741,40 @@ pub(crate) async fn ensure_matching_spec<Block: BlockT + DeserializeOwned>(
 	}
 }
 
+/// Get the spec `(name, version)` of the runtime at the block `at`, or at the best block, of the
+/// node at `uri`.
+pub(crate) async fn remote_spec<Block: BlockT + DeserializeOwned>(
+	uri: &str,
+	at: Option<Block::Hash>,
+) -> sc_cli::Result<(String, u32)> {
+	let rpc = ws_client(uri).await?;
+	StateApi::<Block::Hash>::runtime_version(&rpc, at)
+		.await
+		.map(|version| (version.spec_name.into(), version.spec_version))
+		.map_err(|e| format!("failed to fetch runtime version from {}: {:?}", uri, e).into())
+}
+
+/// Get the header of the block `at`, or of the latest finalized block, from the node at `uri`.
+pub(crate) async fn remote_header<Block: BlockT + DeserializeOwned>(
+	uri: &str,
+	at: Option<Block::Hash>,
+) -> sc_cli::Result<Block::Header>
+where
+	Block::Header: DeserializeOwned,
+{
+	let rpc = ws_client(uri).await?;
+	let at = match at {
+		Some(at) => at,
+		None => ChainApi::<(), Block::Hash, Block::Header, ()>::finalized_head(&rpc)
+			.await
+			.map_err(|e| format!("failed to fetch the finalized head from {}: {:?}", uri, e))?,
+	};
+	ChainApi::<(), Block::Hash, Block::Header, ()>::header(&rpc, Some(at))
+		.await
+		.map_err(|e| format!("failed to fetch header {:?} from {}: {:?}", at, uri, e))?
+		.ok_or_else(|| format!("header {:?} not found on {}", at, uri).into())
+}
+
 /// Build all extensions that we typically use.
 pub(crate) fn full_extensions() -> Extensions {
 	let mut extensions = Extensions::default();
@@ -723,13 +812,35 @@ pub(crate) fn state_machine_call<Block: BlockT, D: NativeExecutionDispatch + 'st
 	ext: &TestExternalities,
 	executor: &NativeElseWasmExecutor<D>,
 	execution: sc_cli::ExecutionStrategy,
-	method: &'static str,
+	method: &str,
 	data: &[u8],
 	extensions: Extensions,
 ) -> sc_cli::Result<(OverlayedChanges, Vec<u8>)> {
+	state_machine_call_with_db_stats::<Block, D>(
+		ext, executor, execution, method, data, extensions, None,
+	)
+	.map(|(changes, encoded_results, _)| (changes, encoded_results))
+}
+
+/// Same as [`state_machine_call`], but it also counts the database operations of the call.
+///
+/// If `max_reads` is set, the call fails once more storage reads than that reach the backend.
+pub(crate) fn state_machine_call_with_db_stats<
+	Block: BlockT,
+	D: NativeExecutionDispatch + 'static,
+>(
+	ext: &TestExternalities,
+	executor: &NativeElseWasmExecutor<D>,
+	execution: sc_cli::ExecutionStrategy,
+	method: &str,
+	data: &[u8],
+	extensions: Extensions,
+	max_reads: Option<u64>,
+) -> sc_cli::Result<(OverlayedChanges, Vec<u8>, DbOpStats)> {
 	let mut changes = Default::default();
+	let counting_backend = CountingBackend::new(&ext.backend).with_read_budget(max_reads);
 	let encoded_results = StateMachine::new(
-		&ext.backend,
+		&counting_backend,
 		&mut changes,
 		executor,
 		method,
@@ -739,24 +850,45 @@ pub(crate) fn state_machine_call<Block: BlockT, D: NativeExecutionDispatch + 'st
 		sp_core::testing::TaskExecutor::new(),
 	)
 	.execute(execution.into())
-	.map_err(|e| format!("failed to execute '{}': {}", method, e))
-	.map_err::<sc_cli::Error, _>(Into::into)?;
+	.map_err(|e| call_error(method, e, &counting_backend))?;
 
-	Ok((changes, encoded_results))
+	let db_stats = counting_backend.stats(&changes);
+	Ok((changes, encoded_results, db_stats))
+}
+
+/// The error of a failed call of `method`, blaming the read budget of `backend` if it was exceeded.
+fn call_error<B>(
+	method: &str,
+	error: impl std::fmt::Display,
+	backend: &CountingBackend<B>,
+) -> sc_cli::Error {
+	match backend.exceeded_budget() {
+		Some(max_reads) => format!(
+			"'{}' exceeded the budget of {} storage reads, is it iterating over unbounded storage?",
+			method, max_reads,
+		),
+		None => format!("failed to execute '{}': {}", method, error),
+	}
+	.into()
 }
 
 /// Same as [`state_machine_call`], but it also computes and prints the storage proof in different
-/// size and formats.
+/// size and formats, and counts the database operations of the call.
+///
+/// `max_reads` is as in [`state_machine_call_with_db_stats`]. If `node_accesses` is set, it is
+/// filled with the hashes of the trie nodes fetched by the call, in the order they were fetched.
 ///
 /// Make sure [`LOG_TARGET`] is enabled in logging.
 pub(crate) fn state_machine_call_with_proof<Block: BlockT, D: NativeExecutionDispatch + 'static>(
 	ext: &TestExternalities,
 	executor: &NativeElseWasmExecutor<D>,
 	execution: sc_cli::ExecutionStrategy,
-	method: &'static str,
+	method: &str,
 	data: &[u8],
 	extensions: Extensions,
-) -> sc_cli::Result<(OverlayedChanges, Vec<u8>)> {
+	max_reads: Option<u64>,
+	node_accesses: Option<&mut Vec<H256>>,
+) -> sc_cli::Result<(OverlayedChanges, Vec<u8>, DbOpStats)> {
 	use parity_scale_codec::Encode;
 	use sp_core::hexdisplay::HexDisplay;
 
@@ -764,15 +896,21 @@ pub(crate) fn state_machine_call_with_proof<Block: BlockT, D: NativeExecutionDis
 	let backend = ext.backend.clone();
 	let runtime_code_backend = sp_state_machine::backend::BackendRuntimeCode::new(&backend);
 
-	let proving_backend =
-		TrieBackendBuilder::wrap(&backend).with_recorder(Default::default()).build();
+	let node_access_recorder = NodeAccessRecorder::<_, sp_core::Blake2Hasher>::new(
+		backend.backend_storage(),
+		node_accesses.is_some(),
+	);
+	let proving_backend = TrieBackendBuilder::new(&node_access_recorder, *backend.root())
+		.with_recorder(Default::default())
+		.build();
 
 	let runtime_code = runtime_code_backend.runtime_code()?;
 
 	let pre_root = *backend.root();
 
+	let counting_backend = CountingBackend::new(&proving_backend).with_read_budget(max_reads);
 	let encoded_results = StateMachine::new(
-		&proving_backend,
+		&counting_backend,
 		&mut changes,
 		executor,
 		method,
@@ -782,8 +920,7 @@ pub(crate) fn state_machine_call_with_proof<Block: BlockT, D: NativeExecutionDis
 		sp_core::testing::TaskExecutor::new(),
 	)
 	.execute(execution.into())
-	.map_err(|e| format!("failed to execute {}: {}", method, e))
-	.map_err::<sc_cli::Error, _>(Into::into)?;
+	.map_err(|e| call_error(method, e, &counting_backend))?;
 
 	let proof = proving_backend
 		.extract_proof()
@@ -825,7 +962,11 @@ pub(crate) fn state_machine_call_with_proof<Block: BlockT, D: NativeExecutionDis
 		"zstd-compressed compact proof {}",
 		humanize(compressed_proof.len()),
 	);
-	Ok((changes, encoded_results))
+	let db_stats = counting_backend.stats(&changes);
+	if let Some(node_accesses) = node_accesses {
+		*node_accesses = node_access_recorder.take_accesses();
+	}
+	Ok((changes, encoded_results, db_stats))
 }
 
 /// Get the spec `(name, version)` from the local runtime.
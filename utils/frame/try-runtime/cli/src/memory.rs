@@ -0,0 +1,100 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2022 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A watchdog for the memory usage of the process.
+
+use std::{thread, time::Duration};
+
+use crate::LOG_TARGET;
+
+/// How often the watchdog checks the memory usage.
+const CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Start a thread that exits the process with an error once its resident memory exceeds
+/// `max_mb` megabytes.
+///
+/// The migration itself cannot be interrupted, so exiting is the only way to stop before the OOM
+/// killer does. The resident memory is only known on Linux; elsewhere this logs a warning and
+/// does nothing.
+pub(crate) fn spawn_memory_watchdog(max_mb: u64) {
+	if resident_memory().is_none() {
+		log::warn!(
+			target: LOG_TARGET,
+			"the memory usage of the process is unknown on this platform, --max-memory-mb is ignored",
+		);
+		return
+	}
+
+	thread::spawn(move || {
+		let used = watch(max_mb.saturating_mul(1024 * 1024), CHECK_INTERVAL, resident_memory);
+		log::error!(
+			target: LOG_TARGET,
+			"exceeded memory budget: {} MB resident, --max-memory-mb is {}",
+			used / (1024 * 1024),
+			max_mb,
+		);
+		std::process::exit(1);
+	});
+}
+
+/// Call `read` every `interval` until it returns more than `max_bytes`, and return that value.
+fn watch(max_bytes: u64, interval: Duration, read: impl Fn() -> Option<u64>) -> u64 {
+	loop {
+		match read() {
+			Some(used) if used > max_bytes => return used,
+			_ => thread::sleep(interval),
+		}
+	}
+}
+
+/// The resident memory of the process, in bytes.
+fn resident_memory() -> Option<u64> {
+	let status = std::fs::read_to_string("/proc/self/status").ok()?;
+	parse_vm_rss(&status)
+}
+
+/// Parse the `VmRSS` line of `/proc/<pid>/status`, into bytes.
+fn parse_vm_rss(status: &str) -> Option<u64> {
+	let line = status.lines().find_map(|line| line.strip_prefix("VmRSS:"))?;
+	let kb = line.trim().strip_suffix("kB")?.trim().parse::<u64>().ok()?;
+	Some(kb * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::cell::RefCell;
+
+	#[test]
+	fn vm_rss_is_parsed() {
+		let status = "Name:\ttry-runtime\nVmPeak:\t  20000 kB\nVmRSS:\t   1234 kB\nThreads:\t4\n";
+		assert_eq!(parse_vm_rss(status), Some(1234 * 1024));
+		assert_eq!(parse_vm_rss("Name:\ttry-runtime\n"), None);
+	}
+
+	#[test]
+	fn watch_returns_once_limit_is_exceeded() {
+		let allocated = RefCell::new(Vec::<Vec<u8>>::new());
+		let used = watch(3 * 1024, Duration::from_millis(1), || {
+			let mut allocated = allocated.borrow_mut();
+			allocated.push(vec![0; 1024]);
+			Some(allocated.iter().map(Vec::len).sum::<usize>() as u64)
+		});
+		assert_eq!(used, 4 * 1024);
+		assert_eq!(allocated.borrow().len(), 4);
+	}
+}
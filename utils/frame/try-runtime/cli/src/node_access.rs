@@ -0,0 +1,103 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2022 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Recording of the order in which a runtime call accesses trie nodes.
+
+use std::sync::Mutex;
+
+use sp_core::Hasher;
+use sp_state_machine::{DBValue, DefaultError, TrieBackendStorage};
+
+/// A [`TrieBackendStorage`] that forwards to `inner` and records the hash of every trie node
+/// fetched from it, in the order they are fetched.
+///
+/// Nodes fetched more than once are recorded each time, so the sequence reflects the access
+/// pattern of the call rather than only the set of nodes in its proof. Nothing is recorded
+/// unless `record` is set, as the sequence of a large call takes a lot of memory.
+pub(crate) struct NodeAccessRecorder<'a, S, H: Hasher> {
+	inner: &'a S,
+	record: bool,
+	accesses: Mutex<Vec<H::Out>>,
+}
+
+impl<'a, S, H: Hasher> NodeAccessRecorder<'a, S, H> {
+	pub fn new(inner: &'a S, record: bool) -> Self {
+		Self { inner, record, accesses: Default::default() }
+	}
+
+	/// Take the hashes of the nodes fetched so far, in the order they were fetched.
+	pub fn take_accesses(&self) -> Vec<H::Out> {
+		std::mem::take(&mut *self.accesses.lock().unwrap_or_else(|poisoned| poisoned.into_inner()))
+	}
+}
+
+impl<'a, S: TrieBackendStorage<H>, H: Hasher> TrieBackendStorage<H>
+	for NodeAccessRecorder<'a, S, H>
+{
+	type Overlay = S::Overlay;
+
+	fn get(
+		&self,
+		key: &H::Out,
+		prefix: (&[u8], Option<u8>),
+	) -> Result<Option<DBValue>, DefaultError> {
+		if self.record {
+			self.accesses.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push(*key);
+		}
+		self.inner.get(key, prefix)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use remote_externalities::TestExternalities;
+	use sp_core::{storage::Storage, Blake2Hasher, H256};
+	use sp_state_machine::{Backend, TrieBackendBuilder};
+
+	/// The nodes accessed by reading `keys` from `ext`, in order.
+	fn accesses_of(ext: &TestExternalities, keys: &[&[u8]]) -> Vec<H256> {
+		let recorder =
+			NodeAccessRecorder::<_, Blake2Hasher>::new(ext.backend.backend_storage(), true);
+		let backend = TrieBackendBuilder::new(&recorder, *ext.backend.root())
+			.with_recorder(Default::default())
+			.build();
+		for key in keys {
+			assert!(backend.storage(key).unwrap().is_some());
+		}
+		recorder.take_accesses()
+	}
+
+	#[test]
+	fn nodes_are_recorded_in_access_order() {
+		// values long enough for the leaves not to be inlined into the branch node, but short
+		// enough to be stored in the leaves rather than in nodes of their own.
+		let mut storage = Storage::default();
+		storage.top.insert(b"a".to_vec(), vec![1; 32]);
+		storage.top.insert(b"b".to_vec(), vec![2; 32]);
+		let ext = TestExternalities::new(storage);
+		let root = *ext.backend.root();
+
+		let a_then_b = accesses_of(&ext, &[b"a", b"b"]);
+		assert_eq!(a_then_b.len(), 4);
+		assert_eq!((a_then_b[0], a_then_b[2]), (root, root));
+		assert_ne!(a_then_b[1], a_then_b[3]);
+
+		let b_then_a = accesses_of(&ext, &[b"b", b"a"]);
+		assert_eq!(b_then_a, [root, a_then_b[3], root, a_then_b[1]]);
+	}
+}
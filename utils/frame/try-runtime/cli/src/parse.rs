@@ -18,8 +18,14 @@
 //! Utils for parsing user input
 
 use sp_version::StateVersion;
+use std::io::BufRead;
 
+/// Parse a block hash, or read it from the standard input if `block_hash` is `-`.
 pub(crate) fn hash(block_hash: &str) -> Result<String, String> {
+	if block_hash == "-" {
+		return hash_from_reader(std::io::stdin().lock())
+	}
+
 	let (block_hash, offset) = if let Some(block_hash) = block_hash.strip_prefix("0x") {
 		(block_hash, 2)
 	} else {
@@ -36,6 +42,44 @@ pub(crate) fn hash(block_hash: &str) -> Result<String, String> {
 	}
 }
 
+fn hash_from_reader(mut reader: impl BufRead) -> Result<String, String> {
+	let mut line = String::new();
+	reader
+		.read_line(&mut line)
+		.map_err(|e| format!("Failed to read block hash from stdin: {}", e))?;
+	match line.trim() {
+		"-" => Err("Expected block hash on stdin, found '-'".into()),
+		block_hash => hash(block_hash),
+	}
+}
+
+pub(crate) fn hex_bytes(s: &str) -> Result<Vec<u8>, String> {
+	sp_core::bytes::from_hex(s).map_err(|e| format!("Invalid hex: {}", e))
+}
+
+/// Parse a `<hex prefix>=<count>` pair.
+pub(crate) fn prefix_count(s: &str) -> Result<(Vec<u8>, usize), String> {
+	let (prefix, count) = s
+		.split_once('=')
+		.ok_or_else(|| format!("Expected <prefix>=<count>, found {}", s))?;
+	let count = count.parse().map_err(|e| format!("Invalid count {}: {}", count, e))?;
+	Ok((hex_bytes(prefix)?, count))
+}
+
+/// Parse a runtime api call as `<method>[:<hex arguments>]`.
+///
+/// The arguments are empty if not given.
+pub(crate) fn runtime_call(s: &str) -> Result<(String, Vec<u8>), String> {
+	let (method, args) = match s.split_once(':') {
+		Some((method, args)) => (method, hex_bytes(args)?),
+		None => (s, Vec::new()),
+	};
+	if method.is_empty() {
+		return Err(format!("Expected <method>[:<hex arguments>], found {}", s))
+	}
+	Ok((method.to_string(), args))
+}
+
 pub(crate) fn url(s: &str) -> Result<String, &'static str> {
 	if s.starts_with("ws://") || s.starts_with("wss://") {
 		// could use Url crate as well, but lets keep it simple for now.
@@ -45,9 +89,53 @@ pub(crate) fn url(s: &str) -> Result<String, &'static str> {
 	}
 }
 
+pub(crate) fn http_url(s: &str) -> Result<String, &'static str> {
+	if s.starts_with("http://") || s.starts_with("https://") {
+		Ok(s.to_string())
+	} else {
+		Err("not a valid HTTP(S) url: must start with 'http://' or 'https://'")
+	}
+}
+
 pub(crate) fn state_version(s: &str) -> Result<StateVersion, &'static str> {
 	s.parse::<u8>()
 		.map_err(|_| ())
 		.and_then(StateVersion::try_from)
 		.map_err(|_| "Invalid state version.")
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn hash_is_read_from_reader() {
+		assert_eq!(hash_from_reader(&b"0xabcd\n"[..]), Ok("abcd".to_string()));
+		assert!(hash_from_reader(&b"-\n"[..]).is_err());
+		assert!(hash_from_reader(&b"0xnothex\n"[..]).is_err());
+	}
+
+	#[test]
+	fn runtime_call_works() {
+		assert_eq!(runtime_call("MyApi_check"), Ok(("MyApi_check".to_string(), vec![])));
+		assert_eq!(runtime_call("MyApi_check:0x0102"), Ok(("MyApi_check".to_string(), vec![1, 2])));
+		assert!(runtime_call("MyApi_check:0xzz").is_err());
+		assert!(runtime_call(":0x0102").is_err());
+	}
+
+	#[test]
+	fn http_url_works() {
+		assert!(http_url("http://127.0.0.1:8080/hook").is_ok());
+		assert!(http_url("https://ci.example.com/hook").is_ok());
+		assert!(http_url("ws://127.0.0.1:9944").is_err());
+		assert!(http_url("ci.example.com/hook").is_err());
+	}
+
+	#[test]
+	fn prefix_count_works() {
+		assert_eq!(prefix_count("0x1234=10"), Ok((vec![0x12, 0x34], 10)));
+		assert!(prefix_count("0x1234").is_err());
+		assert!(prefix_count("0x1234=ten").is_err());
+		assert!(prefix_count("0xzz=10").is_err());
+	}
+}
@@ -0,0 +1,177 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2022 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Posting of command results to a webhook.
+
+use std::time::Duration;
+
+use hyper::{client::HttpConnector, header, Body, Client, Method, Request};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+
+use crate::LOG_TARGET;
+
+/// The timeout of a single request to the webhook, including reading the response status.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// POST the JSON `body` to the `http://` or `https://` `url`, retrying up to `retries` times.
+///
+/// The delay before a retry starts at `retry_delay` and doubles with each retry. Any `2xx` status
+/// is a success.
+pub(crate) async fn post_json(
+	url: &str,
+	body: &str,
+	retries: u32,
+	retry_delay: Duration,
+) -> Result<(), String> {
+	let connector = HttpsConnectorBuilder::new()
+		.with_native_roots()
+		.https_or_http()
+		.enable_http1()
+		.build();
+	let client = Client::builder().build(connector);
+
+	let mut delay = retry_delay;
+	let mut attempt = 0;
+	loop {
+		match post(&client, url, body).await {
+			Ok(()) => return Ok(()),
+			Err(e) if attempt < retries => {
+				log::warn!(
+					target: LOG_TARGET,
+					"posting to {} failed: {}, retrying in {:?}",
+					url,
+					e,
+					delay,
+				);
+				tokio::time::sleep(delay).await;
+				delay *= 2;
+				attempt += 1;
+			},
+			Err(e) => return Err(format!("posting to {} failed {} times: {}", url, attempt + 1, e)),
+		}
+	}
+}
+
+async fn post(
+	client: &Client<HttpsConnector<HttpConnector>, Body>,
+	url: &str,
+	body: &str,
+) -> Result<(), String> {
+	let request = Request::builder()
+		.method(Method::POST)
+		.uri(url)
+		.header(header::CONTENT_TYPE, "application/json")
+		.body(Body::from(body.to_owned()))
+		.map_err(|e| e.to_string())?;
+	let response = tokio::time::timeout(TIMEOUT, client.request(request))
+		.await
+		.map_err(|_| format!("no response within {:?}", TIMEOUT))?
+		.map_err(|e| e.to_string())?;
+	if response.status().is_success() {
+		Ok(())
+	} else {
+		Err(format!("status {}", response.status().as_u16()))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::{
+		io::{BufRead, BufReader, Read, Write},
+		net::{TcpListener, TcpStream},
+		sync::mpsc,
+		thread,
+	};
+
+	/// Read one request from `stream`, returning its body, and answer it with `status`.
+	fn serve(stream: TcpStream, status: &str) -> String {
+		let mut reader = BufReader::new(stream.try_clone().unwrap());
+		let mut content_length = 0;
+		loop {
+			let mut line = String::new();
+			reader.read_line(&mut line).unwrap();
+			if line == "\r\n" {
+				break
+			}
+			if let Some(length) = line.to_lowercase().strip_prefix("content-length:") {
+				content_length = length.trim().parse().unwrap();
+			}
+		}
+		let mut body = vec![0; content_length];
+		reader.read_exact(&mut body).unwrap();
+		write!(&stream, "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status)
+			.unwrap();
+		String::from_utf8(body).unwrap()
+	}
+
+	fn block_on<T>(future: impl std::future::Future<Output = T>) -> T {
+		tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap()
+			.block_on(future)
+	}
+
+	#[test]
+	fn json_is_posted_with_retries() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let url = format!("http://{}/hooks/try-runtime", listener.local_addr().unwrap());
+		let (tx, rx) = mpsc::channel();
+		thread::spawn(move || {
+			let mut incoming = listener.incoming();
+			serve(incoming.next().unwrap().unwrap(), "503 Service Unavailable");
+			tx.send(serve(incoming.next().unwrap().unwrap(), "200 OK")).unwrap();
+		});
+
+		let body = r#"{"failed":0}"#;
+		block_on(post_json(&url, body, 1, Duration::from_millis(1))).unwrap();
+		assert_eq!(rx.recv().unwrap(), body);
+	}
+
+	#[test]
+	fn failures_are_reported_after_retries() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let url = format!("http://{}", listener.local_addr().unwrap());
+		thread::spawn(move || {
+			for stream in listener.incoming().take(2) {
+				serve(stream.unwrap(), "500 Internal Server Error");
+			}
+		});
+
+		let err = block_on(post_json(&url, "{}", 1, Duration::from_millis(1))).unwrap_err();
+		assert!(err.ends_with("failed 2 times: status 500"), "{}", err);
+	}
+
+	#[test]
+	fn https_is_spoken_to_https_urls() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let url = format!("https://{}", listener.local_addr().unwrap());
+		// a server that only speaks plain http, so the TLS handshake fails.
+		let server = thread::spawn(move || {
+			let (mut stream, _) = listener.accept().unwrap();
+			let mut hello = [0; 1];
+			stream.read_exact(&mut hello).unwrap();
+			let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+			// 0x16 is the content type of a TLS handshake record.
+			hello[0]
+		});
+
+		assert!(block_on(post_json(&url, "{}", 0, Duration::from_millis(1))).is_err());
+		assert_eq!(server.join().unwrap(), 0x16);
+	}
+}